@@ -6,8 +6,39 @@
 @group(0) @binding(1) var texture_sampler: sampler;
 struct PostProcessSettings {
     time: f32,
+    curvature: vec2<f32>,
+    target_size: vec2<f32>,
+    lines: f32,
+    scanline_opacity: f32,
+    vignette_opacity: f32,
+    vignette_roundness: f32,
+    syncline_size: f32,
+    syncline_speed: f32,
+    syncline_intensity: f32,
+    brightness: f32,
+    warmup: f32,
+    phosphor: vec3<f32>,
+    monochrome: f32,
+    persistence: f32,
+    decay: f32,
 }
 @group(0) @binding(2) var<uniform> settings: PostProcessSettings;
+@group(0) @binding(3) var history_texture: texture_2d<f32>;
+
+struct FragmentOutput {
+    @location(0) colour: vec4<f32>,
+    @location(1) history: vec4<f32>, // read back next frame
+}
+
+// virtual scanlines down and across, square and at least two physical pixels each
+fn virtual_resolution() -> vec2<f32> {
+    let size = settings.target_size;
+    if (size.x <= 0.0 || size.y <= 0.0) {
+        return vec2<f32>(settings.lines, settings.lines * 2.0); // size unknown for the first frame
+    }
+    let lines = min(settings.lines, size.y * 0.5);
+    return vec2<f32>(lines, lines * size.x / size.y);
+}
 
 fn curved_transform(in: FullscreenVertexOutput, curvature: vec2<f32>) -> vec2<f32> {
     var uv = in.uv * 2.0 - 1.0;
@@ -38,21 +69,30 @@ fn syncline_intensity(y: f32, size: f32, speed: f32) -> f32 {
     }
 }
 
+// bright pixels fade over a few frames instead of vanishing
+fn persist(in: FullscreenVertexOutput, colour: vec4<f32>) -> FragmentOutput {
+    let previous = textureSample(history_texture, texture_sampler, in.uv);
+    let persisted = vec4<f32>(max(colour.rgb, previous.rgb * settings.decay), colour.a);
+    return FragmentOutput(persisted, persisted);
+}
+
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
-    let curvature = vec2<f32>(8.0, 8.0);
-    let uv = curved_transform(in, curvature);
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
+    let uv = curved_transform(in, settings.curvature);
     if uv.x < 0 || uv.y < 0 || uv.x > 1 || uv.y > 1 {
-        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        let black = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return FragmentOutput(black, black);
     } else {
-        let resolution = vec2<f32>(480.0, 960.0) * 0.75; // externalise, derive from screen resolution
+        let resolution = virtual_resolution();
         var colour = textureSample(screen_texture, texture_sampler, uv);
-        colour *= vignette_intensity(uv, resolution, 1.0, 1.0);
-        colour *= scanline_intensity(uv.x, resolution.y, 1.0); // vertical
-        colour *= scanline_intensity(uv.y, resolution.x, 1.0); // horizontal
-        let syncline = syncline_intensity(uv.y, 6.0, 5.0) * 0.5;
-        let warmup = 1.0 - 1.0 / (1.0 * settings.time + 1.0); // reciprocal brightening over time
-        colour *= vec4<f32>(vec3<f32>(3.0 + syncline), 1.0) * warmup; // brightness
-        return colour;
+        let luminance = dot(colour.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+        colour = vec4<f32>(mix(colour.rgb, luminance * settings.phosphor, settings.monochrome), colour.a);
+        colour *= vignette_intensity(uv, resolution, settings.vignette_opacity, settings.vignette_roundness);
+        colour *= scanline_intensity(uv.x, resolution.y, settings.scanline_opacity); // vertical
+        colour *= scanline_intensity(uv.y, resolution.x, settings.scanline_opacity); // horizontal
+        let syncline = syncline_intensity(uv.y, settings.syncline_size, settings.syncline_speed) * settings.syncline_intensity;
+        let warmup = 1.0 - 1.0 / (settings.warmup * settings.time + 1.0); // reciprocal brightening over time
+        colour *= vec4<f32>(vec3<f32>(settings.brightness + syncline), 1.0) * warmup; // brightness
+        return persist(in, colour);
     }
 }
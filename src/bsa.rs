@@ -0,0 +1,602 @@
+//! Bethesda archive (BSA) reading, version 104 as used by Fallout 3 and New Vegas.
+//!
+//! An archive is a header, a sorted table of folder records, each folder's
+//! file records, a block of file names, then the file data.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use flate2::read::ZlibDecoder;
+
+/// Archive version used by Fallout 3 and New Vegas.
+pub const VERSION: u32 = 104;
+
+/// Folder names are stored before each folder's file records.
+pub const FLAG_DIRECTORY_NAMES: u32 = 0x0001;
+/// File names are stored in a block after the file records.
+pub const FLAG_FILE_NAMES: u32 = 0x0002;
+/// Files are compressed unless their record says otherwise.
+pub const FLAG_COMPRESSED: u32 = 0x0004;
+/// File data is prefixed with its full path.
+pub const FLAG_EMBED_NAMES: u32 = 0x0100;
+
+/// File record size bit inverting the archive's default compression.
+const SIZE_COMPRESSION: u32 = 0x4000_0000;
+
+/// Deflate expands at most about 1032:1, caps preallocation for sizes read from the archive.
+const MAX_RATIO: usize = 1032;
+
+/// Archive read error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error), // Underlying reader failed.
+    Magic([u8; 4]), // File doesn't start with BSA\0.
+    Version(u32), // Unsupported archive version.
+    Truncated(&'static str), // Data ended inside the named structure.
+    NotFound(String), // No such file in the archive.
+    Compression(String), // Compressed file failed to inflate.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Magic(magic) => write!(f, "not an archive (found {:?})", String::from_utf8_lossy(magic)),
+            Error::Version(version) => write!(f, "unsupported archive version {version}"),
+            Error::Truncated(what) => write!(f, "truncated {what}"),
+            Error::NotFound(path) => write!(f, "{path}: not found"),
+            Error::Compression(path) => write!(f, "{path}: failed to decompress"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated("archive"),
+            _ => Error::Io(error),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Archive header.
+#[derive(Debug, Default, Clone, Copy)]
+#[allow(dead_code)] // the index is read in order, offsets and name lengths are only shown by `Debug`
+pub struct Header {
+    pub version: u32,
+    pub offset: u32, // Offset of the folder records, always 36.
+    pub flags: u32, // See the FLAG_ constants.
+    pub folder_count: u32,
+    pub file_count: u32,
+    pub folder_names_length: u32, // Total length of all folder names.
+    pub file_names_length: u32, // Total length of all file names.
+    pub file_flags: u32, // Content types, meshes, textures, etc.
+}
+
+/// A folder and the files directly inside it.
+#[derive(Debug, Default, Clone)]
+pub struct Folder {
+    pub hash: u64,
+    pub name: String, // Empty without FLAG_DIRECTORY_NAMES.
+    pub files: Vec<FileRecord>, // Sorted by hash.
+}
+
+/// A file entry.
+#[derive(Debug, Default, Clone)]
+pub struct FileRecord {
+    pub hash: u64,
+    pub name: String, // Empty without FLAG_FILE_NAMES.
+    pub size: u32, // Stored size, including any embedded name and size prefix.
+    pub offset: u32, // Absolute offset of the data.
+    pub compressed: bool, // Resolved against the archive default.
+}
+
+/// Archive index, data is read from `path` on demand.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub path: PathBuf,
+    pub header: Header,
+    pub folders: Vec<Folder>, // Sorted by hash.
+}
+
+impl Archive {
+    /// Open an archive and read its index.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let (header, folders) = read_index(&mut reader)?;
+        Ok(Archive { path: path.to_path_buf(), header, folders })
+    }
+
+    /// Iterate all files with their full paths.
+    pub fn files(&self) -> impl Iterator<Item = (String, &FileRecord)> {
+        self.folders.iter().flat_map(|folder| {
+            folder.files.iter().map(move |file| (format!("{}\\{}", folder.name, file.name), file))
+        })
+    }
+
+    /// Look up a file by path using the name hashes.
+    pub fn find(&self, path: &str) -> Option<&FileRecord> {
+        let path = normalize(path);
+        let (folder, file) = path.rsplit_once('\\').unwrap_or(("", &path));
+        let folder = self.folder(folder)?;
+        let hash = hash_file(file);
+        let index = folder.files.binary_search_by_key(&hash, |file| file.hash).ok()?;
+        Some(&folder.files[index])
+    }
+
+    /// Look up a folder by path.
+    pub fn folder(&self, path: &str) -> Option<&Folder> {
+        let hash = hash_folder(&normalize(path));
+        let index = self.folders.binary_search_by_key(&hash, |folder| folder.hash).ok()?;
+        Some(&self.folders[index])
+    }
+
+    /// Whether a folder exists, either explicitly or as a parent of one.
+    pub fn is_directory(&self, path: &str) -> bool {
+        let path = normalize(path);
+        let path = path.trim_end_matches('\\');
+        path.is_empty() || self.folders.iter().any(|folder| {
+            folder.name == path || folder.name.strip_prefix(path).is_some_and(|rest| rest.starts_with('\\'))
+        })
+    }
+
+    /// Read and decompress a file.
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let file = self.find(path).ok_or_else(|| Error::NotFound(path.into()))?;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        self.extract(&mut reader, file).map_err(|error| match error {
+            Error::Compression(_) => Error::Compression(path.into()),
+            error => error,
+        })
+    }
+
+    /// Read and decompress a file record from an open reader.
+    pub fn extract(&self, mut reader: impl Read + Seek, file: &FileRecord) -> Result<Vec<u8>, Error> {
+        reader.seek(SeekFrom::Start(file.offset as u64))?;
+        let mut size = file.size as usize;
+
+        // skip embedded path
+        if self.header.flags & FLAG_EMBED_NAMES != 0 {
+            let length = read_u8(&mut reader)? as usize;
+            reader.seek(SeekFrom::Current(length as i64))?;
+            size = size.checked_sub(length + 1).ok_or(Error::Truncated("embedded name"))?;
+        }
+
+        if !file.compressed {
+            return read_bytes(&mut reader, size, "file");
+        }
+
+        let original = read_u32(&mut reader)? as usize;
+        let size = size.checked_sub(4).ok_or(Error::Truncated("compressed file"))?;
+        let mut data = Vec::with_capacity(original.min(size.saturating_mul(MAX_RATIO)));
+        ZlibDecoder::new(reader.take(size as u64)).take(original as u64 + 1).read_to_end(&mut data)
+            .map_err(|_| Error::Compression(file.name.clone()))?;
+        if data.len() != original {
+            return Err(Error::Compression(file.name.clone()));
+        }
+        Ok(data)
+    }
+}
+
+/// Read the header, folder records, file records and names.
+pub fn read_index(mut reader: impl Read) -> Result<(Header, Vec<Folder>), Error> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != *b"BSA\0" {
+        return Err(Error::Magic(magic));
+    }
+
+    let header = Header {
+        version: read_u32(&mut reader)?,
+        offset: read_u32(&mut reader)?,
+        flags: read_u32(&mut reader)?,
+        folder_count: read_u32(&mut reader)?,
+        file_count: read_u32(&mut reader)?,
+        folder_names_length: read_u32(&mut reader)?,
+        file_names_length: read_u32(&mut reader)?,
+        file_flags: read_u32(&mut reader)?,
+    };
+    if header.version != VERSION {
+        return Err(Error::Version(header.version));
+    }
+    let default_compressed = header.flags & FLAG_COMPRESSED != 0;
+
+    // folder records: hash, file count, offset, counts aren't trusted for preallocation
+    let mut counts = Vec::new();
+    let mut folders = Vec::new();
+    for _ in 0..header.folder_count {
+        let hash = read_u64(&mut reader)?;
+        counts.push(read_u32(&mut reader)?);
+        read_u32(&mut reader)?; // offset, the blocks follow in order anyway
+        folders.push(Folder { hash, ..Default::default() });
+    }
+
+    // file record blocks, each optionally prefixed by the folder name
+    for (folder, count) in folders.iter_mut().zip(counts) {
+        if header.flags & FLAG_DIRECTORY_NAMES != 0 {
+            let length = read_u8(&mut reader)? as usize;
+            let mut name = vec![0u8; length];
+            reader.read_exact(&mut name)?;
+            folder.name = zstring(&name);
+        }
+        for _ in 0..count {
+            let hash = read_u64(&mut reader)?;
+            let size = read_u32(&mut reader)?;
+            let offset = read_u32(&mut reader)?;
+            folder.files.push(FileRecord {
+                hash,
+                name: String::new(),
+                size: size & !(SIZE_COMPRESSION | 0x8000_0000),
+                offset,
+                compressed: default_compressed != (size & SIZE_COMPRESSION != 0),
+            });
+        }
+    }
+
+    // file names in record order
+    if header.flags & FLAG_FILE_NAMES != 0 {
+        let names = read_bytes(&mut reader, header.file_names_length as usize, "file names")?;
+        let mut names = names.split(|&b| b == 0);
+        for file in folders.iter_mut().flat_map(|folder| folder.files.iter_mut()) {
+            let name = names.next().ok_or(Error::Truncated("file names"))?;
+            file.name = zstring(name);
+        }
+    }
+
+    Ok((header, folders))
+}
+
+//------------------------------------------------------------------------------
+
+/// Lower case with backslash separators, as hashed by the engine.
+pub fn normalize(path: &str) -> String {
+    path.to_lowercase().replace('/', "\\").trim_matches('\\').to_string()
+}
+
+/// Hash a normalized folder path.
+pub fn hash_folder(path: &str) -> u64 {
+    hash(path.as_bytes(), b"")
+}
+
+/// Hash a normalized file name, without its folder.
+pub fn hash_file(name: &str) -> u64 {
+    match name.rfind('.') {
+        Some(dot) => {
+            let (stem, extension) = name.as_bytes().split_at(dot);
+            hash(stem, extension)
+        },
+        None => hash(name.as_bytes(), b""),
+    }
+}
+
+/// The engine's name hash over a stem and extension including the dot.
+fn hash(stem: &[u8], extension: &[u8]) -> u64 {
+    let length = stem.len();
+    let mut low = 0u32;
+    if length > 0 {
+        low = stem[length - 1] as u32
+            | (if length > 2 { stem[length - 2] as u32 } else { 0 }) << 8
+            | (length as u32) << 16
+            | (stem[0] as u32) << 24;
+    }
+    low |= match extension {
+        b".kf" => 0x80,
+        b".nif" => 0x8000,
+        b".dds" => 0x8080,
+        b".wav" => 0x8000_0000,
+        _ => 0,
+    };
+
+    let rolling = |bytes: &[u8]| bytes.iter().fold(0u32, |hash, &b| hash.wrapping_mul(0x1003F).wrapping_add(b as u32));
+    let middle = if length > 3 { rolling(&stem[1..length - 2]) } else { 0 };
+    let high = middle.wrapping_add(rolling(extension));
+
+    (high as u64) << 32 | low as u64
+}
+
+/// Match a normalized path against a glob supporting `*` and `?`.
+pub fn glob(pattern: &str, path: &str) -> bool {
+    let (pattern, path): (Vec<char>, Vec<char>) = (normalize(pattern).chars().collect(), path.chars().collect());
+    let (mut p, mut s) = (0, 0);
+    let mut star = None; // pattern index after the last star, and the path index it matched from
+    while s < path.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, s));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == path[s] => {
+                p += 1;
+                s += 1;
+            },
+            _ => match star {
+                Some((after, from)) => {
+                    star = Some((after, from + 1));
+                    p = after;
+                    s = from + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read a length taken from the archive, growing the buffer only as data arrives.
+fn read_bytes(reader: &mut impl Read, length: usize, what: &'static str) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(Error::Truncated(what));
+    }
+    Ok(bytes)
+}
+
+/// Null terminated string, decoded as latin-1.
+fn zstring(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end].iter().map(|&b| b as char).collect()
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::{Cursor, Write};
+
+    /// A file to store: path inside its folder, contents and the compression toggle bit.
+    type Entry<'a> = (&'a str, &'a [u8], bool);
+
+    /// Write a version 104 archive, sorting folders and files by hash like the engine.
+    fn build(flags: u32, folders: &[(&str, &[Entry])]) -> Vec<u8> {
+        let mut folders: Vec<(&str, Vec<Entry>)> = folders.iter().map(|(name, files)| (*name, files.to_vec())).collect();
+        folders.sort_by_key(|(name, _)| hash_folder(name));
+        for (_, files) in &mut folders {
+            files.sort_by_key(|(name, _, _)| hash_file(name));
+        }
+        let files = || folders.iter().flat_map(|(folder, files)| files.iter().map(move |file| (*folder, file)));
+        let file_count = files().count();
+        let file_names_length: usize = files().map(|(_, (name, _, _))| name.len() + 1).sum();
+        let folder_names_length: usize = folders.iter().map(|(name, _)| name.len() + 1).sum();
+
+        // stored data, optionally prefixed by the full path and compressed
+        let default_compressed = flags & FLAG_COMPRESSED != 0;
+        let stored: Vec<(Vec<u8>, bool)> = files().map(|(folder, &(name, data, toggle))| {
+            let mut stored = Vec::new();
+            if flags & FLAG_EMBED_NAMES != 0 {
+                let path = format!("{folder}\\{name}");
+                stored.push(path.len() as u8);
+                stored.extend(path.as_bytes());
+            }
+            if default_compressed != toggle {
+                stored.extend((data.len() as u32).to_le_bytes());
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                stored.extend(encoder.finish().unwrap());
+            } else {
+                stored.extend(data);
+            }
+            (stored, toggle)
+        }).collect();
+
+        let mut bytes = Vec::new();
+        bytes.extend(b"BSA\0");
+        for value in [VERSION, 36, flags, folders.len() as u32, file_count as u32, folder_names_length as u32, file_names_length as u32, 0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for (name, files) in &folders {
+            bytes.extend(hash_folder(name).to_le_bytes());
+            bytes.extend((files.len() as u32).to_le_bytes());
+            bytes.extend(0u32.to_le_bytes());
+        }
+
+        // data follows the file record blocks and the names
+        let blocks: usize = folders.iter().map(|(name, files)| name.len() + 2 + 16 * files.len()).sum();
+        let mut offset = bytes.len() + blocks + file_names_length;
+        let mut stored_files = stored.iter();
+        for (name, files) in &folders {
+            bytes.push(name.len() as u8 + 1);
+            bytes.extend(name.as_bytes());
+            bytes.push(0);
+            for (file, _, _) in files {
+                let (data, toggle) = stored_files.next().unwrap();
+                let toggle = if *toggle { SIZE_COMPRESSION } else { 0 };
+                bytes.extend(hash_file(file).to_le_bytes());
+                bytes.extend((data.len() as u32 | toggle).to_le_bytes());
+                bytes.extend((offset as u32).to_le_bytes());
+                offset += data.len();
+            }
+        }
+        for (_, (name, _, _)) in files() {
+            bytes.extend(name.as_bytes());
+            bytes.push(0);
+        }
+        for (data, _) in &stored {
+            bytes.extend(data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn hashes() {
+        // computed by hand from the hash description, `game_archive_hashes` checks shipped archives
+        assert_eq!(hash_folder("meshes"), 0x322f3a9a6d066573);
+        assert_eq!(hash_folder("textures\\armor\\power"), 0x3ec7778b74146572);
+        assert_eq!(hash_folder("sound\\fx"), 0xeda95b2073086678);
+        assert_eq!(hash_file("rock.nif"), 0x92cd466c7204e36b);
+        assert_eq!(hash_file("idle.kf"), 0x1711e44d69046ce5);
+        assert_eq!(hash_file("door.dds"), 0x8ddbaa346404eff2);
+        assert_eq!(hash_file("hit.wav"), 0x9733cf9ee8036974);
+        assert_eq!(hash_file("readme.txt"), 0xc7eddcea72066d65);
+    }
+
+    /// Every stored hash in the archives of a game data directory matches its name.
+    #[test]
+    #[ignore = "needs game archives, set WORMHOLE_DATA to a Fallout 3 Data directory"]
+    fn game_archive_hashes() {
+        let data = std::env::var_os("WORMHOLE_DATA").expect("WORMHOLE_DATA is not set");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(data).unwrap().flatten() {
+            if !entry.file_name().to_string_lossy().to_lowercase().ends_with(".bsa") {
+                continue;
+            }
+            let archive = Archive::open(entry.path()).unwrap();
+            for folder in &archive.folders {
+                assert_eq!(hash_folder(&folder.name), folder.hash, "{}: {}", entry.path().display(), folder.name);
+                for file in &folder.files {
+                    assert_eq!(hash_file(&file.name), file.hash, "{}: {}\\{}", entry.path().display(), folder.name, file.name);
+                    checked += 1;
+                }
+            }
+        }
+        assert!(checked > 0, "no archives found");
+    }
+
+    #[test]
+    fn hashes_short() {
+        assert_eq!(hash_folder(""), 0);
+        assert_eq!(hash_folder("a"), 0x61010061);
+        assert_eq!(hash_folder("ab"), 0x61020062);
+        assert_eq!(hash_folder("abc"), 0x61036263);
+        assert_eq!(hash_folder("abcd"), 0x6261046364);
+        assert_eq!(hash_file("a.nif"), 0x92cd45fd61018061);
+        assert_eq!(hash_file("ab.dds"), 0x8ddba9c5610280e2);
+        assert_eq!(hash_file("noext"), 0x6f1bb66e057874);
+        assert_eq!(hash_file("x"), 0x78010078);
+        assert_eq!(hash(b"rock", b".nif"), hash_file("rock.nif"));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("meshes\\*.nif", "meshes\\rock.nif"));
+        assert!(glob("Meshes/*.NIF", "meshes\\rock.nif"));
+        assert!(glob("*", "meshes\\rock.nif"));
+        assert!(glob("meshes\\r?ck.*", "meshes\\rock.nif"));
+        assert!(glob("*\\*\\*.dds", "textures\\armor\\power.dds"));
+        assert!(glob("*a*b*", "xaxxbx"));
+        assert!(!glob("meshes\\*.nif", "meshes\\rock.dds"));
+        assert!(!glob("r?ck", "rck"));
+        assert!(!glob("textures\\*", "meshes\\rock.nif"));
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn index() {
+        let flags = FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES | FLAG_COMPRESSED | FLAG_EMBED_NAMES;
+        let bytes = build(flags, &[
+            ("meshes\\rocks", &[("rock01.nif", b"rock one", false), ("rock02.nif", b"rock two", true)]),
+            ("textures", &[("sky.dds", b"blue sky", false)]),
+        ]);
+        let (header, folders) = read_index(Cursor::new(&bytes)).unwrap();
+        assert_eq!((header.folder_count, header.file_count), (2, 3));
+        assert!(folders.windows(2).all(|pair| pair[0].hash < pair[1].hash));
+
+        let archive = Archive { path: PathBuf::new(), header, folders };
+        let mut names: Vec<String> = archive.files().map(|(path, _)| path).collect();
+        names.sort();
+        assert_eq!(names, ["meshes\\rocks\\rock01.nif", "meshes\\rocks\\rock02.nif", "textures\\sky.dds"]);
+
+        // the toggle bit inverts the compressed default
+        let rock01 = archive.find("Meshes/Rocks/Rock01.nif").unwrap();
+        let rock02 = archive.find("meshes\\rocks\\rock02.nif").unwrap();
+        assert!(rock01.compressed && !rock02.compressed);
+        assert_eq!(rock02.size, 1 + "meshes\\rocks\\rock02.nif".len() as u32 + 8);
+
+        // data skips the embedded names
+        assert_eq!(archive.extract(Cursor::new(&bytes), rock01).unwrap(), b"rock one");
+        assert_eq!(archive.extract(Cursor::new(&bytes), rock02).unwrap(), b"rock two");
+        let sky = archive.find("textures/sky.dds").unwrap();
+        assert_eq!(archive.extract(Cursor::new(&bytes), sky).unwrap(), b"blue sky");
+
+        assert!(archive.find("textures/cloud.dds").is_none());
+        assert!(archive.find("meshes/rock01.nif").is_none());
+        assert!(archive.is_directory("meshes") && archive.is_directory("meshes/rocks/"));
+        assert!(!archive.is_directory("mesh"));
+    }
+
+    #[test]
+    fn index_uncompressed_default() {
+        let bytes = build(FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES, &[
+            ("sound", &[("a.wav", b"plain", false), ("b.wav", b"packed", true)]),
+        ]);
+        let (header, folders) = read_index(Cursor::new(&bytes)).unwrap();
+        let archive = Archive { path: PathBuf::new(), header, folders };
+        let (a, b) = (archive.find("sound/a.wav").unwrap(), archive.find("sound/b.wav").unwrap());
+        assert!(!a.compressed && b.compressed);
+        assert_eq!(archive.extract(Cursor::new(&bytes), a).unwrap(), b"plain");
+        assert_eq!(archive.extract(Cursor::new(&bytes), b).unwrap(), b"packed");
+    }
+
+    #[test]
+    fn extract_size_mismatch() {
+        let mut bytes = build(FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES | FLAG_COMPRESSED, &[("a", &[("b.nif", b"contents", false)])]);
+        let (header, folders) = read_index(Cursor::new(&bytes)).unwrap();
+        let archive = Archive { path: PathBuf::new(), header, folders };
+        let file = archive.find("a/b.nif").unwrap().clone();
+
+        // an original size far beyond the stored data must not be trusted
+        let offset = file.offset as usize;
+        bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(archive.extract(Cursor::new(&bytes), &file), Err(Error::Compression(_))));
+        bytes[offset..offset + 4].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(archive.extract(Cursor::new(&bytes), &file), Err(Error::Compression(_))));
+    }
+
+    #[test]
+    fn index_errors() {
+        assert!(matches!(read_index(Cursor::new(b"BSB\0")), Err(Error::Magic(_))));
+        let mut bytes = build(FLAG_FILE_NAMES, &[("a", &[("b.nif", b"c", false)])]);
+        bytes[4] = 103;
+        assert!(matches!(read_index(Cursor::new(&bytes)), Err(Error::Version(103))));
+        bytes[4] = 104;
+        bytes.truncate(50);
+        assert!(matches!(read_index(Cursor::new(&bytes)), Err(Error::Truncated(_))));
+    }
+
+    #[test]
+    fn index_bogus_counts() {
+        // counts far beyond the data fail as truncated instead of allocating
+        let mut bytes = build(FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES, &[("a", &[("b.nif", b"c", false)])]);
+        bytes[16..20].copy_from_slice(&u32::MAX.to_le_bytes()); // folder count
+        assert!(matches!(read_index(Cursor::new(&bytes)), Err(Error::Truncated(_))));
+
+        let mut bytes = build(FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES, &[("a", &[("b.nif", b"c", false)])]);
+        bytes[28..32].copy_from_slice(&u32::MAX.to_le_bytes()); // file names length
+        assert!(matches!(read_index(Cursor::new(&bytes)), Err(Error::Truncated("file names"))));
+
+        let bytes = build(FLAG_DIRECTORY_NAMES | FLAG_FILE_NAMES, &[("a", &[("b.nif", b"c", false)])]);
+        let (header, folders) = read_index(Cursor::new(&bytes)).unwrap();
+        let mut file = folders[0].files[0].clone();
+        file.size = u32::MAX >> 2;
+        let archive = Archive { path: PathBuf::new(), header, folders };
+        assert!(matches!(archive.extract(Cursor::new(&bytes), &file), Err(Error::Truncated("file"))));
+    }
+}
@@ -0,0 +1,404 @@
+//! Cell spawning, placing the references of an interior or exterior cell.
+//!
+//! References (REFR, ACHR, ACRE) are resolved across the load order, later
+//! plugins overriding earlier ones, and spawned as scenes of their base
+//! object's model. Placements are converted from Gamebryo Z-up to Bevy Y-up.
+
+use std::{f32::consts::FRAC_PI_2, fmt};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::console::{ConsoleArgs, StdErrEvent, StdOutEvent};
+use crate::esm::{self, FormId, GroupKind, Record};
+use crate::load_order::LoadOrder;
+
+/// Exterior cell size in game units.
+pub const CELL_SIZE: f32 = 4096.0;
+
+/// Reference records placed in cells.
+const REFERENCES: [&[u8; 4]; 3] = [b"REFR", b"ACHR", b"ACRE"];
+
+/// A cell to spawn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CellId {
+    Interior(String), // Cell EditorID.
+    Exterior { world: String, x: i32, y: i32 }, // Worldspace EditorID and grid coordinate.
+}
+
+impl fmt::Display for CellId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CellId::Interior(editor_id) => write!(f, "{editor_id}"),
+            CellId::Exterior { world, x, y } => write!(f, "{world} {x},{y}"),
+        }
+    }
+}
+
+/// Cell lookup error.
+#[derive(Debug)]
+pub enum Error {
+    NoPlugins, // Nothing has been loaded yet.
+    World(String), // No worldspace with this EditorID.
+    Cell(CellId), // No such cell.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoPlugins => write!(f, "no plugins loaded"),
+            Error::World(world) => write!(f, "{world}: no such worldspace"),
+            Error::Cell(cell) => write!(f, "{cell}: no such cell"),
+        }
+    }
+}
+
+/// Request to replace the current cell.
+#[derive(Event)]
+pub struct SpawnCell(pub CellId);
+
+/// Marker for the root entity of the spawned cell.
+#[derive(Component)]
+pub struct CellRoot;
+
+/// A reference ready to spawn.
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub id: FormId, // Reference form id.
+    pub model: String, // Asset path of the base object's model.
+    pub transform: Transform, // Y-up placement.
+}
+
+//------------------------------------------------------------------------------
+
+/// Resolve the placements of a cell across the load order.
+pub fn resolve(order: &LoadOrder, cell: &CellId) -> Result<Vec<Placement>, Error> {
+    if order.is_empty() {
+        return Err(Error::NoPlugins);
+    }
+    let cell_id = find_cell(order, cell)?;
+
+    // later plugins override references by global form id
+    let mut references: HashMap<FormId, (usize, &Record)> = HashMap::new();
+    for (index, loaded) in order.iter() {
+        let Some(local) = order.local(index, cell_id) else { continue; };
+        let Some(children) = loaded.plugin.find_group(GroupKind::CellChildren(local)) else { continue; };
+        for record in children.descendants().filter(|record| REFERENCES.contains(&&record.header.kind)) {
+            references.insert(order.global(index, record.header.id), (index, record));
+        }
+    }
+    references.retain(|_, (_, record)| record.header.flags & (esm::FLAG_DELETED | esm::FLAG_DISABLED) == 0);
+
+    // one pass per plugin to find the winning base objects
+    let base = |index: usize, record: &Record| Some(order.global(index, base(record)?));
+    let mut models: HashMap<FormId, String> = references.values()
+        .filter_map(|&(index, record)| base(index, record))
+        .map(|id| (id, String::new()))
+        .collect();
+    for (index, loaded) in order.iter() {
+        for record in loaded.plugin.records() {
+            if let Some(model) = models.get_mut(&order.global(index, record.header.id)) {
+                *model = record.get(b"MODL").map(|field| field.zstring()).unwrap_or_default();
+            }
+        }
+    }
+
+    let mut placements: Vec<_> = references.iter()
+        .filter_map(|(&id, &(index, record))| {
+            let model = models.get(&base(index, record)?).filter(|model| is_visible(model))?;
+            Some(Placement { id, model: model_path(model), transform: transform(record)? })
+        })
+        .collect();
+    placements.sort_by_key(|placement| placement.id);
+    Ok(placements)
+}
+
+/// Find a cell's global form id, preferring the last plugin defining it.
+fn find_cell(order: &LoadOrder, cell: &CellId) -> Result<FormId, Error> {
+    let is_cell = |record: &&Record| record.header.kind == *b"CELL";
+    let found = match cell {
+        CellId::Interior(editor_id) => find_editor_id(order, editor_id, b"CELL"),
+        CellId::Exterior { world, x, y } => {
+            let world_id = find_editor_id(order, world, b"WRLD").ok_or_else(|| Error::World(world.clone()))?;
+            order.iter().rev().find_map(|(index, loaded)| {
+                let group = loaded.plugin.find_group(GroupKind::WorldChildren(order.local(index, world_id)?))?;
+                let record = group.descendants().filter(is_cell).find(|record| grid(record) == Some((*x, *y)))?;
+                Some(order.global(index, record.header.id))
+            })
+        }
+    };
+    found.ok_or_else(|| Error::Cell(cell.clone()))
+}
+
+/// Global form id of a record of one type by EditorID, case insensitive.
+fn find_editor_id(order: &LoadOrder, editor_id: &str, kind: &[u8; 4]) -> Option<FormId> {
+    order.editor_id(editor_id).filter(|found| found.kind == *kind).map(|found| found.id)
+}
+
+/// Exterior cell grid coordinate from XCLC.
+fn grid(record: &Record) -> Option<(i32, i32)> {
+    let field = record.get(b"XCLC")?;
+    Some((field.u32(0)? as i32, field.u32(4)? as i32))
+}
+
+/// Base object of a reference from NAME.
+fn base(record: &Record) -> Option<FormId> {
+    record.get(b"NAME")?.u32(0).map(FormId)
+}
+
+/// Editor markers have models but are hidden in game.
+fn is_visible(model: &str) -> bool {
+    let name = model.rsplit(['\\', '/']).next().unwrap_or(model);
+    !model.is_empty() && !name.to_lowercase().starts_with("marker")
+}
+
+/// Convert a model path relative to the meshes folder to an asset path.
+fn model_path(path: &str) -> String {
+    let path = path.to_lowercase().replace('\\', "/");
+    let path = path.trim_start_matches('/');
+    let path = path.strip_prefix("meshes/").unwrap_or(path);
+    format!("{}://meshes/{path}", crate::data::SOURCE)
+}
+
+/// Reference placement from DATA and XSCL, converted to Y-up.
+///
+/// DATA holds a Z-up position and XYZ euler angles in radians, applied X first
+/// and clockwise. Models are rotated to Y-up by their scene root, so the
+/// placement is conjugated by the same basis change.
+fn transform(record: &Record) -> Option<Transform> {
+    let data = record.get(b"DATA")?;
+    let [x, y, z, rx, ry, rz] = [0, 4, 8, 12, 16, 20].map(|offset| data.f32(offset));
+    let position = Vec3::new(x?, y?, z?);
+    let rotation = Quat::from_rotation_z(-rz?) * Quat::from_rotation_y(-ry?) * Quat::from_rotation_x(-rx?);
+    let scale = record.get(b"XSCL").and_then(|field| field.f32(0)).unwrap_or(1.0);
+
+    let basis = Quat::from_rotation_x(-FRAC_PI_2);
+    Some(Transform {
+        translation: basis * position,
+        rotation: basis * rotation * basis.inverse(),
+        scale: Vec3::splat(scale),
+    })
+}
+
+//------------------------------------------------------------------------------
+
+/// Replace the current cell with the requested one.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_cell(
+    mut commands: Commands,
+    mut events: EventReader<SpawnCell>,
+    order: Res<LoadOrder>,
+    asset_server: Res<AssetServer>,
+    cells: Query<Entity, With<CellRoot>>,
+    mut cameras: Query<&mut Transform, With<Camera3d>>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    for SpawnCell(cell) in events.read() {
+        let placements = match resolve(&order, cell) {
+            Ok(placements) => placements,
+            Err(error) => {
+                stderr.send(StdErrEvent::new(format!("coc: {error}\n")));
+                continue;
+            }
+        };
+
+        for entity in &cells {
+            commands.entity(entity).despawn_recursive();
+        }
+        commands.spawn((SpatialBundle::default(), CellRoot, Name::new(cell.to_string())))
+            .with_children(|parent| {
+                for placement in &placements {
+                    parent.spawn(SceneBundle {
+                        scene: asset_server.load(&placement.model),
+                        transform: placement.transform,
+                        ..default()
+                    });
+                }
+            });
+
+        // look at the middle of the cell from above
+        let center = match cell {
+            _ if !placements.is_empty() => {
+                placements.iter().map(|placement| placement.transform.translation).sum::<Vec3>() / placements.len() as f32
+            },
+            CellId::Exterior { x, y, .. } => Vec3::new(*x as f32 + 0.5, 0.0, -(*y as f32 + 0.5)) * CELL_SIZE,
+            CellId::Interior(_) => Vec3::ZERO,
+        };
+        for mut transform in &mut cameras {
+            *transform = Transform::from_translation(center + Vec3::new(0.0, 512.0, 1024.0)).looking_at(center, Vec3::Y);
+        }
+
+        stdout.send(StdOutEvent::new(format!("{cell}: {} references\n", placements.len())));
+    }
+}
+
+/// Center on cell, by interior EditorID or worldspace grid coordinate.
+pub fn command_coc(
+    In(args): In<ConsoleArgs>,
+    mut cells: EventWriter<SpawnCell>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    const USAGE: &str = "EditorID | worldspace x y";
+    if let Err(error) = args.expect(1..=3, USAGE) {
+        stderr.send(error);
+        return;
+    }
+
+    let cell = match &args.args[..] {
+        [editor_id] => CellId::Interior(editor_id.clone()),
+        [world, x, y] => match (x.parse(), y.parse()) {
+            (Ok(x), Ok(y)) => CellId::Exterior { world: world.clone(), x, y },
+            _ => {
+                stderr.send(StdErrEvent::new(format!("coc: {x},{y}: invalid grid coordinate\n")));
+                return;
+            }
+        },
+        _ => {
+            stderr.send(StdErrEvent::new(format!("usage: coc {USAGE}\n")));
+            return;
+        }
+    };
+    cells.send(SpawnCell(cell));
+}
+
+/// Complete cell and worldspace EditorIDs for `coc`.
+pub fn complete_coc(In(args): In<ConsoleArgs>, order: Res<LoadOrder>) -> Vec<String> {
+    if args.args.len() != 1 {
+        return Vec::new();
+    }
+    order.editor_ids()
+        .filter(|found| matches!(&found.kind, b"CELL" | b"WRLD"))
+        .map(|found| found.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esm::{Entry, Group, GroupHeader, Header, Plugin, RecordHeader};
+
+    /// A record holding the given subrecords.
+    fn record(kind: &[u8; 4], flags: u32, id: u32, fields: &[(&[u8; 4], Vec<u8>)]) -> Record {
+        let mut data = Vec::new();
+        for (kind, field) in fields {
+            data.extend(*kind);
+            data.extend((field.len() as u16).to_le_bytes());
+            data.extend(field);
+        }
+        let header = RecordHeader { kind: *kind, size: data.len() as u32, flags, id: FormId(id), ..Default::default() };
+        Record { header, data }
+    }
+
+    fn group(kind: GroupKind, records: Vec<Record>) -> Group {
+        let header = GroupHeader { size: 0, kind, stamp: 0, unknown: 0, version: 0, unknown2: 0 };
+        Group { header, entries: records.into_iter().map(Entry::Record).collect() }
+    }
+
+    fn zstring(value: &str) -> Vec<u8> {
+        [value.as_bytes(), b"\0"].concat()
+    }
+
+    /// DATA with a position and rotation.
+    fn data(position: [f32; 3], rotation: [f32; 3]) -> Vec<u8> {
+        position.iter().chain(&rotation).flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn reference(flags: u32, id: u32, base: u32, position: [f32; 3]) -> Record {
+        record(b"REFR", flags, id, &[(b"NAME", base.to_le_bytes().to_vec()), (b"DATA", data(position, [0.0; 3]))])
+    }
+
+    fn stat(id: u32, editor_id: &str, model: &str) -> Record {
+        record(b"STAT", 0, id, &[(b"EDID", zstring(editor_id)), (b"MODL", zstring(model))])
+    }
+
+    fn plugin(masters: &[&str], groups: Vec<Group>) -> Plugin {
+        Plugin { header: Header { masters: masters.iter().map(|&master| master.into()).collect(), ..Default::default() }, groups }
+    }
+
+    /// Fallout3.esm with a cell of four references, Mod.esp editing it.
+    fn load_order() -> LoadOrder {
+        let mut order = LoadOrder::default();
+        let master = plugin(&[], vec![
+            group(GroupKind::Top(*b"STAT"), vec![
+                stat(0x0801, "Rock", r"Rocks\Rock01.NIF"),
+                stat(0x0802, "Marker", r"Markers\MarkerX.nif"),
+            ]),
+            group(GroupKind::Top(*b"CELL"), vec![record(b"CELL", 0, 0x0900, &[(b"EDID", zstring("Vault"))])]),
+            group(GroupKind::CellChildren(FormId(0x0900)), vec![
+                reference(0, 0x0A01, 0x0801, [1.0, 2.0, 3.0]),
+                reference(0, 0x0A02, 0x0802, [0.0; 3]),
+                reference(0, 0x0A03, 0x0801, [0.0; 3]),
+                reference(esm::FLAG_DISABLED, 0x0A04, 0x0801, [0.0; 3]),
+            ]),
+        ]);
+        let edit = plugin(&["Fallout3.esm"], vec![
+            group(GroupKind::Top(*b"STAT"), vec![stat(0x0801, "Rock", r"Meshes\Rocks\RockMod.nif")]),
+            group(GroupKind::CellChildren(FormId(0x0900)), vec![
+                reference(0, 0x0A01, 0x0801, [10.0, 20.0, 30.0]),
+                reference(esm::FLAG_DELETED, 0x0A03, 0x0801, [0.0; 3]),
+                reference(0, 0x0100_0B00, 0x0801, [0.0; 3]),
+            ]),
+        ]);
+        order.push("Fallout3.esm", master).unwrap();
+        order.push("Mod.esp", edit).unwrap();
+        order
+    }
+
+    #[test]
+    fn resolve_overrides() {
+        let order = load_order();
+        let placements = resolve(&order, &CellId::Interior("vault".into())).unwrap();
+        let found: Vec<_> = placements.iter().map(|placement| (placement.id.0, placement.model.as_str())).collect();
+        // the marker, the deleted and the disabled references are dropped
+        assert_eq!(found, [(0x0A01, "bsa://meshes/rocks/rockmod.nif"), (0x0100_0B00, "bsa://meshes/rocks/rockmod.nif")]);
+        assert!(placements[0].transform.translation.abs_diff_eq(Vec3::new(10.0, 30.0, -20.0), 1e-5));
+
+        assert!(matches!(resolve(&order, &CellId::Interior("Rock".into())), Err(Error::Cell(_))));
+        assert!(matches!(resolve(&LoadOrder::default(), &CellId::Interior("Vault".into())), Err(Error::NoPlugins)));
+        let exterior = CellId::Exterior { world: "Wasteland".into(), x: 0, y: 0 };
+        assert!(matches!(resolve(&order, &exterior), Err(Error::World(world)) if world == "Wasteland"));
+    }
+
+    #[test]
+    fn transforms() {
+        let placed = |rotation, scale: Option<f32>| {
+            let mut fields = vec![(b"DATA", data([1.0, 2.0, 3.0], rotation))];
+            fields.extend(scale.map(|scale| (b"XSCL", scale.to_le_bytes().to_vec())));
+            transform(&record(b"REFR", 0, 0, &fields)).unwrap()
+        };
+
+        // Z-up to Y-up, game north (+Y) is -Z
+        let placement = placed([0.0; 3], None);
+        assert!(placement.translation.abs_diff_eq(Vec3::new(1.0, 3.0, -2.0), 1e-6));
+        assert_eq!(placement.scale, Vec3::ONE);
+        assert!(placement.rotation.abs_diff_eq(Quat::IDENTITY, 1e-6));
+
+        // positive angles turn clockwise about game Z, which is up
+        let placement = placed([0.0, 0.0, FRAC_PI_2], Some(2.0));
+        assert!((placement.rotation * Vec3::X).abs_diff_eq(Vec3::Z, 1e-6));
+        assert_eq!(placement.scale, Vec3::splat(2.0));
+
+        // and clockwise about game X, turning game +Y (Bevy -Z) to game -Z (Bevy -Y)
+        let placement = placed([FRAC_PI_2, 0.0, 0.0], None);
+        assert!((placement.rotation * Vec3::NEG_Z).abs_diff_eq(Vec3::NEG_Y, 1e-6));
+
+        assert!(transform(&record(b"REFR", 0, 0, &[(b"DATA", vec![0; 20])])).is_none());
+    }
+
+    #[test]
+    fn model_paths() {
+        assert_eq!(model_path(r"Clutter\Food\Apple.NIF"), "bsa://meshes/clutter/food/apple.nif");
+        assert_eq!(model_path(r"Meshes\Rocks\Rock01.nif"), "bsa://meshes/rocks/rock01.nif");
+        assert_eq!(model_path(r"\meshes\rocks\rock01.nif"), "bsa://meshes/rocks/rock01.nif");
+        assert_eq!(model_path("architecture/meshes/wall.nif"), "bsa://meshes/architecture/meshes/wall.nif");
+    }
+
+    #[test]
+    fn visibility() {
+        assert!(is_visible(r"Rocks\Rock01.nif"));
+        assert!(is_visible(r"Markers\Rock.nif"));
+        assert!(!is_visible(r"Markers\MarkerX.nif"));
+        assert!(!is_visible("MARKERHEADING.NIF"));
+        assert!(!is_visible(""));
+    }
+}
@@ -10,109 +10,411 @@ use bevy::{
     },
     ecs::system::SystemId,
     prelude::*,
-    utils::HashMap,
+    ui::RelativeCursorPosition,
 };
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env,
+    fs,
+    io,
+    ops::{Index, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::cvar::{CVarChanged, CVarExt, CVarValue, CVars};
+use crate::shell;
 
 /// Developer console plugin.
 pub struct ConsolePlugin;
 
 impl Plugin for ConsolePlugin {
     fn build(&self, app: &mut App) {
-        println!("ConsolePlugin::build()");
+        info!("ConsolePlugin::build()");
         app.add_event::<StdOutEvent>();
         app.add_event::<StdErrEvent>();
         app.init_resource::<CommandMap>();
-        app.add_systems(Startup, (setup_console, console_greeter).chain());
-        app.add_systems(Update, (cursor_tick, console_input, console_output, console_error, console_scroll));
+        app.register_console_command("debug", "Print debug information.", command_debug);
+        app.register_console_command("exec", "Run console commands from a file: exec file.cfg.", command_exec);
+        app.register_console_completer("exec", complete_exec);
+        app.register_console_command("exit", "Exit wormhole.", command_exit);
+        app.register_console_command("help", "Display this help text.", command_help);
+        app.register_console_command("system", "Print system information.", command_system);
+        app.register_console_command("version", "Build information.", command_version);
+        app.register_cvar("console_font_size", 16.0, "Console text size in pixels.");
+        app.register_cvar("console_color", Color::srgb_u8(41, 225, 140), "Console text color.");
+        app.register_cvar("console_error_color", Color::srgb_u8(255, 176, 0), "Console error text color.");
+        app.register_cvar("console_error_prefix", "", "Text put before each error line.");
+        app.register_cvar("console_show_errors", true, "Print errors to the console.");
+        app.register_cvar("console_split_errors", false, "Print errors in their own pane below the output.");
+        app.register_cvar("console_scrollback", 1000i64, "Output lines kept, older lines are dropped.");
+        app.register_cvar("console_height", 33.0, "Console height in percent of the window.");
+        app.register_cvar("console_slide_time", 0.2, "Seconds to slide the console in or out.");
+        app.init_state::<ConsoleOpen>();
+        app.init_resource::<CursorBlink>();
+        app.add_systems(Startup, (setup_console, console_greeter, console_autoexec).chain());
+        app.add_systems(Update, (
+            (cursor_tick, console_input.run_if(in_state(ConsoleOpen(true))), input_display).chain(),
+            console_print, console_scroll.run_if(in_state(ConsoleOpen(true))),
+        ));
+        app.add_systems(Update, (console_cvars, console_split_errors, console_toggle, console_slide));
     }
 }
 
 //------------------------------------------------------------------------------
 
 const GREET: &str = "ROBCO INDUSTRIES (TM) TERMLINK PROTOCOL\n";
+const HISTORY_SIZE: usize = 100;
+const AUTOEXEC: &str = "autoexec.cfg";
+const EXEC_DEPTH: usize = 8; // Scripts nested by exec.
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const HELP: &str = r#"debug               Print debug information.
-exit                Exit wormhole.
-help                Display this help text.
-load filename       Load an ESM or BSA file.
-system              Print system information.
-version             Build information.
-"#;
 
 /// Some sort of debug information or mode.
 fn command_debug(
+    In(args): In<ConsoleArgs>,
     mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
 ) {
-    stdout.send(StdOutEvent { value: "unimplemented\n".into() });
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
+    stdout.send(StdOutEvent::new("unimplemented\n"));
 }
 
 /// Exit the Bevy app.
 fn command_exit(
+    In(args): In<ConsoleArgs>,
     mut exit: EventWriter<AppExit>,
+    mut stderr: EventWriter<StdErrEvent>,
 ) {
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
     exit.send(AppExit::Success);
 }
 
-/// Load some asset.
-fn command_load(
-    mut stdout: EventWriter<StdOutEvent>,
-) {
-    stdout.send(StdOutEvent { value: "unimplemented\n".into() });
-}
-
 /// Print system information.
 fn command_system(
+    In(args): In<ConsoleArgs>,
     mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
     system: Res<SystemInfo>,
 ) {
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
     let &SystemInfo { os, kernel, cpu, core_count, memory } = &system.into_inner();
-    stdout.send(StdOutEvent { value: format!(r#"OS:     {os}
+    stdout.send(StdOutEvent::new(format!(r#"OS:     {os}
 Kernel: {kernel}
 CPU:    {cpu}
 Cores:  {core_count}
 Memory: {memory}
-"#) });
+"#)));
+}
+
+/// List registered commands.
+fn command_help(
+    In(args): In<ConsoleArgs>,
+    commands: Res<CommandMap>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
+    let mut value = String::new();
+    for (name, command) in &commands.0 {
+        value.push_str(&format!("{name:<19} {}\n", command.help));
+    }
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Print build information.
+fn command_version(
+    In(args): In<ConsoleArgs>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
+    stdout.send(StdOutEvent::new(format!("{NAME} {VERSION}\n")));
+}
+
+//------------------------------------------------------------------------------
+
+/// Registers console commands on the app, for use by any plugin.
+pub trait ConsoleCommandExt {
+    /// Add a command run as a system with its arguments, listed by `help` with a one line description.
+    fn register_console_command<M>(
+        &mut self,
+        name: &str,
+        help: &str,
+        system: impl IntoSystem<ConsoleArgs, (), M> + 'static,
+    ) -> &mut Self;
+
+    /// Add an argument completer to a registered command, returning candidates for the last argument.
+    fn register_console_completer<M>(
+        &mut self,
+        name: &str,
+        system: impl IntoSystem<ConsoleArgs, Vec<String>, M> + 'static,
+    ) -> &mut Self;
+}
+
+impl ConsoleCommandExt for App {
+    fn register_console_command<M>(
+        &mut self,
+        name: &str,
+        help: &str,
+        system: impl IntoSystem<ConsoleArgs, (), M> + 'static,
+    ) -> &mut Self {
+        let world = self.world_mut();
+        let system = world.register_system(system);
+        let command = ConsoleCommand { system, help: help.into(), completer: None };
+        world.get_resource_or_insert_with(CommandMap::default).0.insert(name.into(), command);
+        self
+    }
+
+    fn register_console_completer<M>(
+        &mut self,
+        name: &str,
+        system: impl IntoSystem<ConsoleArgs, Vec<String>, M> + 'static,
+    ) -> &mut Self {
+        let world = self.world_mut();
+        let system = world.register_system(system);
+        let mut commands = world.get_resource_or_insert_with(CommandMap::default);
+        let command = commands.0.get_mut(name).unwrap_or_else(|| panic!("completer for unregistered command {name}"));
+        command.completer = Some(system);
+        self
+    }
+}
+
+/// Run each line of a script file as console commands.
+///
+/// Nested `exec` lines are expanded in place, a queued `exec` can't run while
+/// this one is running.
+fn command_exec(
+    In(args): In<ConsoleArgs>,
+    mut commands: Commands,
+    binaries: Res<CommandMap>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(1..=1, "file.cfg") {
+        stderr.send(error);
+        return;
+    }
+    let mut script = Vec::new();
+    expand_script(&args[0], &mut Vec::new(), &mut script);
+    for line in script {
+        match line {
+            Ok(args) => queue(&args, &mut commands, &binaries, &mut stderr),
+            Err(error) => { stderr.send(StdErrEvent::new(error)); },
+        }
+    }
 }
 
-// Map of all Bevy console commands.
-impl FromWorld for CommandMap {
-    fn from_world(world: &mut World) -> Self {
-        let mut commands = CommandMap(HashMap::new());
+/// Expand a script into its commands in order, with an error for each line that failed.
+fn expand_script(path: &str, stack: &mut Vec<PathBuf>, script: &mut Vec<Result<Vec<String>, String>>) {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+    if stack.contains(&canonical) {
+        script.push(Err(format!("exec: {path}: recursive exec\n")));
+        return;
+    }
+    if stack.len() == EXEC_DEPTH {
+        script.push(Err(format!("exec: {path}: nested more than {EXEC_DEPTH} deep\n")));
+        return;
+    }
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            script.push(Err(format!("exec: {path}: {error}\n")));
+            return;
+        }
+    };
+
+    stack.push(canonical);
+    for (number, line) in text.lines().enumerate().map(|(index, line)| (index + 1, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let commands = match shell::parse(line) {
+            Ok(commands) => commands,
+            Err(error) => {
+                script.push(Err(format!("{path}:{number}: {error}\n")));
+                continue;
+            }
+        };
+        for args in commands {
+            match args.as_slice() {
+                [exec, nested] if exec == "exec" => expand_script(nested, stack, script),
+                [exec, ..] if exec == "exec" => script.push(Err(format!("{path}:{number}: usage: exec file.cfg\n"))),
+                _ => script.push(Ok(args)),
+            }
+        }
+    }
+    stack.pop();
+}
 
-        commands.0.insert("debug".into(), world.register_system(command_debug));
-        commands.0.insert("exit".into(), world.register_system(command_exit));
-        commands.0.insert("load".into(), world.register_system(command_load));
-        commands.0.insert("system".into(),  world.register_system(command_system));
+/// Complete script paths for `exec`.
+fn complete_exec(In(args): In<ConsoleArgs>) -> Vec<String> {
+    match args.args.len() {
+        1 => complete_path(Path::new(""), &args[0], &["cfg"]),
+        _ => Vec::new(),
+    }
+}
 
-        commands
+/// Parse a command line and queue each command in it, implemented as Bevy systems.
+fn dispatch(
+    line: &str,
+    commands: &mut Commands,
+    binaries: &CommandMap,
+    stderr: &mut EventWriter<StdErrEvent>,
+) {
+    let lines = match shell::parse(line) {
+        Ok(lines) => lines,
+        Err(error) => {
+            stderr.send(StdErrEvent::new(format!("{error}\n")));
+            return;
+        }
+    };
+    for args in lines {
+        queue(&args, commands, binaries, stderr);
     }
 }
 
-/// Simple commands that don't require Bevy systems.
-fn shell(
-    stdout: &mut EventWriter<StdOutEvent>,
-    args: Vec<&str>,
+/// Queue one tokenized command.
+fn queue(
+    args: &[String],
+    commands: &mut Commands,
+    binaries: &CommandMap,
+    stderr: &mut EventWriter<StdErrEvent>,
 ) {
-    match args[0] {
-        "help" => stdout.send(StdOutEvent { value: HELP.into() }),
-        "version" => stdout.send(StdOutEvent { value: format!("{NAME} {VERSION}\n").into() }),
-        _ => stdout.send(StdOutEvent { value: format!("unknown command: {}\n", args[0]).into() })
+    match binaries.0.get(&args[0]) {
+        Some(command) => commands.run_system_with_input(command.system, ConsoleArgs::new(args)),
+        None => { stderr.send(StdErrEvent::new(format!("unknown command: {}\n", args[0]))); },
+    }
+}
+
+/// Directories and files with one of the extensions, in the directory of a partial path relative to `base`.
+pub fn complete_path(base: &Path, partial: &str, extensions: &[&str]) -> Vec<String> {
+    let (directory, prefix) = match partial.rfind('/') {
+        Some(index) => partial.split_at(index + 1),
+        None => ("", partial),
+    };
+    let Ok(entries) = fs::read_dir(Path::new(".").join(base).join(directory)) else {
+        return Vec::new();
+    };
+    entries.flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) {
+                return None;
+            }
+            if entry.path().is_dir() {
+                return Some(format!("{directory}{name}/"));
+            }
+            let extension = entry.path().extension()?.to_string_lossy().to_lowercase();
+            extensions.contains(&extension.as_str()).then(|| format!("{directory}{name}"))
+        })
+        .collect()
+}
+
+/// Complete the word before the cursor, run with world access so completers can be systems.
+fn complete(world: &mut World) {
+    let console = world.resource::<ConsoleState>();
+    let (mut tokens, start, partial) = shell::partial(&console.stdin[..console.cursor]);
+
+    // command names, or arguments from the command's completer
+    let mut candidates: Vec<String> = if tokens.is_empty() {
+        world.resource::<CommandMap>().0.keys().cloned().collect()
+    } else {
+        let commands = world.resource::<CommandMap>();
+        let Some(completer) = commands.0.get(&tokens[0]).and_then(|command| command.completer) else { return; };
+        tokens.push(partial.clone());
+        world.run_system_with_input(completer, ConsoleArgs::new(&tokens)).unwrap_or_default()
     };
+    let lowercase = partial.to_lowercase();
+    candidates.retain(|candidate| candidate.to_lowercase().starts_with(&lowercase));
+    candidates.sort();
+    candidates.dedup();
+
+    match candidates.len() {
+        0 => {},
+        1 => {
+            // finish the word, unless more path can follow
+            let mut word = shell::escape(&candidates[0]);
+            if !word.ends_with('/') {
+                word.push(' ');
+            }
+            world.resource_mut::<ConsoleState>().replace_word(start, &word);
+        },
+        _ => {
+            world.send_event(StdOutEvent::new(format!("{}\n", candidates.join("  "))));
+            let candidates: Vec<String> = candidates.iter().map(|candidate| shell::escape(candidate)).collect();
+            let mut console = world.resource_mut::<ConsoleState>();
+            console.replace_word(start, &candidates[0]);
+            console.completion = Some(Completion { start, candidates, index: 0 });
+        },
+    }
 }
 
 //------------------------------------------------------------------------------
 
+/// Order of every write to either stream.
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
 /// Custom write event.
 #[derive(Event)]
 pub struct StdOutEvent {
     pub value: String,
+    order: u64, // Position among writes to both streams.
+}
+
+impl StdOutEvent {
+    /// Write output after everything written so far.
+    pub fn new(value: impl Into<String>) -> Self {
+        Self { value: value.into(), order: WRITES.fetch_add(1, Ordering::Relaxed) }
+    }
 }
 
 /// Custom write event for errors.
 #[derive(Event)]
 pub struct StdErrEvent {
     pub value: String,
+    order: u64, // Position among writes to both streams.
+}
+
+impl StdErrEvent {
+    /// Write an error after everything written so far.
+    pub fn new(value: impl Into<String>) -> Self {
+        Self { value: value.into(), order: WRITES.fetch_add(1, Ordering::Relaxed) }
+    }
+}
+
+/// Stream a line of console text was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Out,
+    Err,
+}
+
+/// Read both streams in the order they were written.
+pub fn read_ordered<'a>(
+    stdout: &'a mut EventReader<StdOutEvent>,
+    stderr: &'a mut EventReader<StdErrEvent>,
+) -> Vec<(Stream, &'a str)> {
+    let mut writes: Vec<(u64, Stream, &str)> = stdout.read()
+        .map(|event| (event.order, Stream::Out, event.value.as_str()))
+        .chain(stderr.read().map(|event| (event.order, Stream::Err, event.value.as_str())))
+        .collect();
+    writes.sort_by_key(|&(order, _, _)| order);
+    writes.into_iter().map(|(_, stream, value)| (stream, value)).collect()
 }
 
 /// Marker for input display.
@@ -127,39 +429,299 @@ pub struct StdOut;
 #[derive(Component)]
 pub struct StdErr;
 
-/// Marker for scrolling output.
+/// Stream of each section of an output text, kept in step with `Text::sections`.
+#[derive(Component, Default)]
+struct Streams(Vec<Stream>);
+
+/// Marker for the output pane, which shares its height with the error pane when split.
 #[derive(Component)]
-struct ConsoleScroll;
+struct OutputPane;
+
+/// Marker for the separate error pane, it shows while errors are split.
+#[derive(Component)]
+struct ErrorPane;
+
+/// Marker for the text of the error pane, it prints errors while it carries `StdErr`.
+#[derive(Component)]
+struct ErrorText;
+
+/// Marker for the console root node.
+#[derive(Component)]
+struct ConsoleRoot;
+
+/// Whether the console is open, gameplay input should only be handled while closed.
+#[derive(States, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConsoleOpen(pub bool);
+
+impl Default for ConsoleOpen {
+    fn default() -> Self {
+        Self(true)
+    }
+}
+
+/// Scroll state of an output list, inside a pane that clips it.
+#[derive(Component)]
+struct ConsoleScroll {
+    position: f32, // Scroll position.
+    follow: bool, // Keep the newest output in view.
+}
+
+impl Default for ConsoleScroll {
+    fn default() -> Self {
+        Self { position: 0.0, follow: true }
+    }
+}
+
+/// Arguments passed to a console command system as `In<ConsoleArgs>`.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleArgs {
+    pub name: String, // Command name.
+    pub args: Vec<String>, // Arguments following the command name.
+}
+
+impl ConsoleArgs {
+    /// Split a tokenized command line into name and arguments.
+    pub fn new(tokens: &[impl AsRef<str>]) -> Self {
+        let Some((name, args)) = tokens.split_first() else {
+            return Self::default();
+        };
+        Self {
+            name: name.as_ref().into(),
+            args: args.iter().map(|arg| arg.as_ref().into()).collect(),
+        }
+    }
+
+    /// Check the argument count, producing a usage error otherwise.
+    pub fn expect(&self, count: RangeInclusive<usize>, usage: &str) -> Result<(), StdErrEvent> {
+        if count.contains(&self.args.len()) {
+            return Ok(());
+        }
+        let usage = format!("{} {usage}", self.name);
+        Err(StdErrEvent::new(format!("usage: {}\n", usage.trim_end())))
+    }
+}
+
+impl Index<usize> for ConsoleArgs {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        &self.args[index]
+    }
+}
 
 /// Map of commands indexed by command name, implemented as Bevy systems.
-#[derive(Resource)]
-struct CommandMap(HashMap<String, SystemId>);
+#[derive(Resource, Default)]
+struct CommandMap(BTreeMap<String, ConsoleCommand>);
+
+/// A registered command.
+struct ConsoleCommand {
+    system: SystemId<ConsoleArgs>, // Runs the command.
+    help: String, // One line description.
+    completer: Option<SystemId<ConsoleArgs, Vec<String>>>, // Candidates for the last argument.
+}
 
 /// Console state.
 #[derive(Resource)]
 struct ConsoleState {
     stdin: String, // Current input buffer.
+    cursor: usize, // Byte offset of the cursor in stdin.
+    style: TextStyle, // Style used for input and output.
+    error_style: TextStyle, // Style used for errors.
+    history: History, // Previously submitted lines.
+    completion: Option<Completion>, // Ambiguous Tab completion being cycled.
+    slide: f32, // Slide animation, 0 closed to 1 open.
+}
+
+/// Blinking input cursor, kept apart from `ConsoleState` so ticking doesn't mark the input changed.
+#[derive(Resource)]
+struct CursorBlink {
     ticker: Timer, // Flashing cursor timer.
-    toggle: bool, // Flashing cursor toggle.
-    style: TextStyle, // Style used for all text.
-    position: f32, // Scroll position.
+    hidden: bool, // Character under the cursor shown instead of the block.
+}
+
+impl Default for CursorBlink {
+    fn default() -> Self {
+        Self { ticker: Timer::from_seconds(1.0, TimerMode::Repeating), hidden: false }
+    }
+}
+
+/// Candidates cycled by repeated Tab presses.
+struct Completion {
+    start: usize, // Byte offset of the word being completed.
+    candidates: Vec<String>,
+    index: usize, // Candidate currently inserted.
+}
+
+impl ConsoleState {
+    /// Replace the input buffer, moving the cursor to the end.
+    fn set_line(&mut self, line: &str) {
+        self.stdin = line.into();
+        self.cursor = self.stdin.len();
+    }
+
+    /// Replace the text from `start` to the cursor.
+    fn replace_word(&mut self, start: usize, word: &str) {
+        self.stdin.replace_range(start..self.cursor, word);
+        self.cursor = start + word.len();
+    }
+
+    /// Insert text at the cursor.
+    fn insert(&mut self, text: &str) {
+        self.stdin.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Byte offset of the character before the cursor.
+    fn previous_char(&self) -> usize {
+        self.stdin[..self.cursor].char_indices().next_back().map_or(0, |(index, _)| index)
+    }
+
+    /// Byte offset of the character after the cursor.
+    fn next_char(&self) -> usize {
+        self.stdin[self.cursor..].chars().next().map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    /// Delete the character before the cursor.
+    fn backspace(&mut self) {
+        let start = self.previous_char();
+        self.stdin.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Delete the character under the cursor.
+    fn delete(&mut self) {
+        let end = self.next_char();
+        self.stdin.replace_range(self.cursor..end, "");
+    }
+
+    /// Delete the word before the cursor and any whitespace following it.
+    fn delete_word(&mut self) {
+        let before = self.stdin[..self.cursor].trim_end();
+        let start = before.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        self.stdin.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Delete everything before the cursor.
+    fn kill_line(&mut self) {
+        self.stdin.replace_range(..self.cursor, "");
+        self.cursor = 0;
+    }
+
+    /// Input split around the cursor: before, under and after it.
+    fn input_sections(&self) -> [&str; 3] {
+        let next = self.next_char();
+        let under = if next == self.cursor { " " } else { &self.stdin[self.cursor..next] };
+        [&self.stdin[..self.cursor], under, &self.stdin[next..]]
+    }
+}
+
+/// Bounded command history with Up/Down recall.
+struct History {
+    lines: VecDeque<String>, // Oldest first.
+    recall: Option<usize>, // Line being recalled, none while editing a new line.
+    draft: String, // New line saved while recalling.
+}
+
+impl History {
+    /// Load history from the config directory, empty if there is none.
+    fn load() -> Self {
+        let text = history_path().and_then(|path| fs::read_to_string(path).ok()).unwrap_or_default();
+        let mut lines: VecDeque<_> = text.lines().filter(|line| !line.is_empty()).map(String::from).collect();
+        while lines.len() > HISTORY_SIZE {
+            lines.pop_front();
+        }
+        Self { lines, recall: None, draft: String::new() }
+    }
+
+    /// Write history to the config directory.
+    fn save(&self) -> io::Result<()> {
+        let path = history_path().ok_or(io::ErrorKind::NotFound)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut text = String::new();
+        for line in &self.lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    /// Add a submitted line, skipping repeats, and stop recalling.
+    fn push(&mut self, line: &str) {
+        self.recall = None;
+        self.draft.clear();
+        if self.lines.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.lines.len() == HISTORY_SIZE {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line.into());
+    }
+
+    /// Recall the previous line, saving the current one when starting.
+    fn previous(&mut self, current: &str) -> Option<&str> {
+        let index = match self.recall {
+            None if self.lines.is_empty() => return None,
+            None => {
+                self.draft = current.into();
+                self.lines.len() - 1
+            },
+            Some(index) => index.saturating_sub(1),
+        };
+        self.recall = Some(index);
+        Some(&self.lines[index])
+    }
+
+    /// Recall the next line, returning to the saved line past the end.
+    fn next(&mut self) -> Option<&str> {
+        let index = self.recall? + 1;
+        if index < self.lines.len() {
+            self.recall = Some(index);
+            return Some(&self.lines[index]);
+        }
+        self.recall = None;
+        Some(&self.draft)
+    }
+}
+
+/// History file in the user's config directory.
+fn history_path() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join(NAME).join("history"))
 }
 
 //------------------------------------------------------------------------------
 
-/// Periodic cursor tick.
+/// Periodic cursor tick, only a blink counts as a change.
 fn cursor_tick(
     time: Res<Time>,
-    mut console: ResMut<ConsoleState>,
+    mut blink: ResMut<CursorBlink>,
+) {
+    if blink.bypass_change_detection().ticker.tick(time.delta()).just_finished() {
+        blink.hidden = !blink.hidden;
+    }
+}
+
+/// Show the input buffer split around the blinking cursor.
+fn input_display(
+    console: Res<ConsoleState>,
+    blink: Res<CursorBlink>,
     mut query: Query<&mut Text, With<StdIn>>,
 ) {
-    if console.ticker.tick(time.delta()).just_finished() {
-        for mut text in &mut query {
-            // sections = [ prompt, stdin, cursor ]
-            if console.toggle { text.sections[2].value = format!("█"); }
-            else { text.sections[2].value = format!(" "); }
-        }
-        console.toggle = !console.toggle;
+    if !console.is_changed() && !blink.is_changed() {
+        return;
+    }
+    let [before, under, after] = console.input_sections();
+    for mut text in &mut query {
+        // sections = [ prompt, before cursor, cursor, after cursor ]
+        text.sections[1].value = before.into();
+        text.sections[2].value = if blink.hidden { under.into() } else { "█".into() };
+        text.sections[3].value = after.into();
     }
 }
 
@@ -167,30 +729,49 @@ fn cursor_tick(
 fn console_greeter(
     mut stdout: EventWriter<StdOutEvent>,
 ) {
-    stdout.send(StdOutEvent { value: GREET.into() });
+    stdout.send(StdOutEvent::new(GREET));
+}
+
+/// Run the autoexec script, if there is one.
+fn console_autoexec(
+    mut commands: Commands,
+    binaries: Res<CommandMap>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if fs::metadata(AUTOEXEC).is_ok() {
+        dispatch(&format!("exec {AUTOEXEC}"), &mut commands, &binaries, &mut stderr);
+    }
 }
 
 /// Create console UI and state.
 fn setup_console(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
+    cvars: Res<CVars>,
 ) {
     // a fallout 3 style terminal
     let style = TextStyle {
         font: asset_server.load("fonts/FSEX300.ttf"), // ye olde font
-        font_size: 16.0,
-        color: Color::srgb_u8(41, 225, 140),
-        ..default()
+        font_size: cvars.float("console_font_size").unwrap_or(16.0).max(1.0),
+        color: cvars.color("console_color").unwrap_or(Color::srgb_u8(41, 225, 140)),
+    };
+    let error_style = TextStyle {
+        color: cvars.color("console_error_color").unwrap_or(Color::srgb_u8(255, 176, 0)),
+        ..style.clone()
     };
+    let split = cvars.bool("console_split_errors").unwrap_or(false);
+    let (output_height, error_height) = pane_heights(split);
 
     // console root node holds everything
     // TODO: alternatively use a flatter layout
     // i.e. remove root node and add background to stdout and stdin
+    let height = cvars.float("console_height").unwrap_or(33.0);
     commands.spawn((
         NodeBundle {
             style: Style {
+                position_type: PositionType::Absolute, // slides over the scene
                 width: Val::Percent(100.0),
-                height: Val::Percent(33.0),
+                height: Val::Percent(height),
                 flex_direction: FlexDirection::Column,
                 justify_content: JustifyContent::FlexStart,
                 align_items: AlignItems::FlexStart,
@@ -206,6 +787,7 @@ fn setup_console(
             background_color: Color::srgb_u8(14, 46, 32).into(),
             ..default()
         },
+        ConsoleRoot,
     )).with_children(|parent| {
         // scrolling output
         parent.spawn(( // container with hidden overflow
@@ -218,12 +800,14 @@ fn setup_console(
                     // the overflow doesn't work unless it has a fixed height
                     // align_self, justify_self and flex_grow do not help
                     // putting stdin inside a NodeBundle doesn't help
-                    height: Val::Percent(94.0),
+                    height: Val::Percent(output_height),
                     overflow: Overflow::clip_y(),
                     ..default()
                 },
                 ..default()
             },
+            RelativeCursorPosition::default(), // the wheel scrolls the hovered pane
+            OutputPane,
         )).with_children(|parent| {
             parent.spawn(( // content wrapper
                 NodeBundle {
@@ -233,12 +817,51 @@ fn setup_console(
                     },
                     ..default()
                 },
-                ConsoleScroll,
+                ConsoleScroll::default(),
             )).with_children(|parent| {
-                parent.spawn(( // content
+                let mut content = parent.spawn(( // content
                     TextBundle::from_section("", style.clone()),
-                    StdOut, StdErr, // print output and errors
+                    Streams(vec![Stream::Out]),
+                    StdOut, // print output, and errors unless they are split
+                ));
+                if !split {
+                    content.insert(StdErr);
+                }
+            });
+        });
+        // errors when split from output, scrolling like output below it
+        parent.spawn((
+            NodeBundle {
+                style: Style {
+                    display: if split { Display::Flex } else { Display::None },
+                    flex_direction: FlexDirection::Column,
+                    height: Val::Percent(error_height),
+                    overflow: Overflow::clip_y(),
+                    ..default()
+                },
+                ..default()
+            },
+            RelativeCursorPosition::default(),
+            ErrorPane,
+        )).with_children(|parent| {
+            parent.spawn((
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        ..default()
+                    },
+                    ..default()
+                },
+                ConsoleScroll::default(),
+            )).with_children(|parent| {
+                let mut errors = parent.spawn((
+                    TextBundle::from_section("", error_style.clone()),
+                    Streams(vec![Stream::Err]),
+                    ErrorText,
                 ));
+                if split {
+                    errors.insert(StdErr);
+                }
             });
         });
         // input
@@ -247,6 +870,7 @@ fn setup_console(
                 TextSection::new(">", style.clone()),
                 TextSection::new("", style.clone()),
                 TextSection::new("█", style.clone()),
+                TextSection::new("", style.clone()),
             ]),
             StdIn,
         ));
@@ -255,28 +879,63 @@ fn setup_console(
     // build the resource
     commands.insert_resource(ConsoleState {
         stdin: String::new(),
-        ticker: Timer::from_seconds(1.0, TimerMode::Repeating),
-        toggle: false,
+        cursor: 0,
         style,
-        position: 0.0,
+        error_style,
+        history: History::load(),
+        completion: None,
+        slide: 1.0,
     })
 }
 
 /// Handle keystrokes.
+#[allow(clippy::too_many_arguments)]
 fn console_input(
     mut commands: Commands,
     mut keyboard_input_events: EventReader<KeyboardInput>,
+    keys: Res<ButtonInput<KeyCode>>,
     mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
     mut console: ResMut<ConsoleState>,
-    mut query: Query<&mut Text, With<StdIn>>,
-    binaries: ResMut<CommandMap>,
+    mut blink: ResMut<CursorBlink>,
+    open: Res<State<ConsoleOpen>>,
+    binaries: Res<CommandMap>,
 ) {
+    if open.is_changed() { // drop keys pressed while closed, including the one that opened it
+        keyboard_input_events.clear();
+        return;
+    }
+    if keyboard_input_events.is_empty() { // leave the input unchanged between keystrokes
+        return;
+    }
+    let console = &mut *console;
+    let control = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let mut changed = false;
     for event in keyboard_input_events.read() {
         if event.state == ButtonState::Released { // ignore release events
             continue;
         }
+        if event.key_code == KeyCode::Backquote { // the toggle key isn't input
+            continue;
+        }
+        changed = true;
+
+        // any other key ends cycling through completions
+        if event.logical_key != Key::Tab {
+            console.completion = None;
+        }
 
         match &event.logical_key {
+            // complete, or cycle through ambiguous completions
+            Key::Tab => match &mut console.completion {
+                Some(completion) => {
+                    completion.index = (completion.index + 1) % completion.candidates.len();
+                    let (start, word) = (completion.start, completion.candidates[completion.index].clone());
+                    console.replace_word(start, &word);
+                },
+                None => commands.add(complete),
+            },
+
             // enter
             Key::Enter => {
                 if console.stdin.is_empty() { // ignore empty buffer
@@ -285,99 +944,443 @@ fn console_input(
 
                 // parse cli input
                 let buffer = console.stdin.clone();
-                stdout.send(StdOutEvent { value: format!(">{}\n", buffer) });
-                let args: Vec<_> = buffer.split_whitespace().collect();
-
-                // try to run a command
-                match args[0] {
-                    // interactive commands are implemented as Bevy systems
-                    "debug" | "exit" | "load" | "system" => {
-                        commands.run_system(binaries.0[args[0]]);
-                    },
-                    _ => shell(&mut stdout, args) // fallback to shell
+                stdout.send(StdOutEvent::new(format!(">{}\n", buffer)));
+                dispatch(&buffer, &mut commands, &binaries, &mut stderr);
+
+                // remember and reset input buffer
+                console.history.push(&buffer);
+                if let Err(error) = console.history.save() {
+                    stderr.send(StdErrEvent::new(format!("history: {error}\n")));
                 }
+                console.set_line("");
+            },
 
-                // reset input buffer
-                console.stdin.clear();
+            // recall history
+            Key::ArrowUp => {
+                if let Some(line) = console.history.previous(&console.stdin) {
+                    let line = line.to_string();
+                    console.set_line(&line);
+                }
+            },
+            Key::ArrowDown => {
+                if let Some(line) = console.history.next() {
+                    let line = line.to_string();
+                    console.set_line(&line);
+                }
             },
 
+            // cursor movement
+            Key::ArrowLeft => console.cursor = console.previous_char(),
+            Key::ArrowRight => console.cursor = console.next_char(),
+            Key::Home if !control => console.cursor = 0,
+            Key::End if !control => console.cursor = console.stdin.len(),
+
             // delete
-            Key::Backspace => {
-                console.stdin.pop();
-            },
+            Key::Backspace => console.backspace(),
+            Key::Delete => console.delete(),
 
-            // every other key
-            Key::Character(input) => {
-                // ignore control chars
-                if input.chars().any(|c| c.is_control()) {
-                    continue;
+            // readline style shortcuts, some platforms report control characters
+            Key::Character(input) if control || input.chars().any(|c| c.is_control()) => {
+                match input.as_str() {
+                    "w" | "W" | "\u{17}" => console.delete_word(),
+                    "u" | "U" | "\u{15}" => console.kill_line(),
+                    _ => {} // ignore other control chars
                 }
-                console.stdin.push_str(&input);
             },
 
+            // every other key
+            Key::Character(input) => console.insert(input),
+            Key::Space => console.insert(" "),
+
             _ => {}
         }
     }
 
-    // update input ui
-    for mut text in &mut query {
-        text.sections[1].value = console.stdin.clone();
+    // show the cursor while typing
+    if changed {
+        blink.hidden = false;
+        blink.ticker.reset();
     }
 }
 
-//------------------------------------------------------------------------------
+/// Open or close the console with the backquote/tilde key.
+fn console_toggle(
+    keys: Res<ButtonInput<KeyCode>>,
+    state: Res<State<ConsoleOpen>>,
+    mut next: ResMut<NextState<ConsoleOpen>>,
+) {
+    if keys.just_pressed(KeyCode::Backquote) {
+        next.set(ConsoleOpen(!state.get().0));
+    }
+}
 
-/// Add output to UI.
-fn console_output(
-    mut stdout: EventReader<StdOutEvent>,
-    mut query: Query<&mut Text, With<StdOut>>,
-    console: Res<ConsoleState>,
+/// Slide the console in from the top or out of view.
+fn console_slide(
+    time: Res<Time>,
+    state: Res<State<ConsoleOpen>>,
+    cvars: Res<CVars>,
+    mut console: ResMut<ConsoleState>,
+    mut query: Query<(&mut Style, &mut Visibility), With<ConsoleRoot>>,
+) {
+    let target: f32 = if state.get().0 { 1.0 } else { 0.0 };
+    let duration = cvars.float("console_slide_time").unwrap_or(0.2).max(f32::EPSILON);
+    let step = time.delta_seconds() / duration;
+    let slide = target.clamp(console.slide - step, console.slide + step);
+    if slide == console.slide && !cvars.is_changed() { // at rest, leave the layout alone
+        return;
+    }
+    console.slide = slide;
+
+    // ease out, fast at first and settling into place
+    let eased = 1.0 - (1.0 - slide).powi(3);
+    let height = cvars.float("console_height").unwrap_or(33.0);
+    for (mut style, mut visibility) in &mut query {
+        style.height = Val::Percent(height);
+        style.top = Val::Percent(-height * (1.0 - eased));
+        *visibility = if slide > 0.0 { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
+
+/// Restyle all console text when its cvars change.
+#[allow(clippy::type_complexity)]
+fn console_cvars(
+    mut changed: EventReader<CVarChanged>,
+    mut console: ResMut<ConsoleState>,
+    mut query: Query<(&mut Text, Option<&Streams>), Or<(With<StdIn>, With<StdOut>, With<StdErr>, With<ErrorText>)>>,
 ) {
-    // TODO: autoscroll
-    for event in stdout.read() {
-        for mut text in &mut query {
-            text.sections.push(TextSection::new(&event.value, console.style.clone()));
+    for CVarChanged { name, value } in changed.read() {
+        match (name.as_str(), value) {
+            ("console_font_size", &CVarValue::Float(size)) => {
+                console.style.font_size = size.max(1.0);
+                console.error_style.font_size = size.max(1.0);
+            },
+            ("console_color", &CVarValue::Color(color)) => console.style.color = color,
+            ("console_error_color", &CVarValue::Color(color)) => console.error_style.color = color,
+            _ => continue,
+        }
+        for (mut text, streams) in &mut query {
+            for (index, section) in text.sections.iter_mut().enumerate() {
+                // input has no streams, it is styled as output
+                section.style = match streams.and_then(|streams| streams.0.get(index)) {
+                    Some(Stream::Err) => console.error_style.clone(),
+                    _ => console.style.clone(),
+                };
+            }
+        }
+    }
+}
+
+/// Heights of the output and error panes in percent, leaving room for input.
+fn pane_heights(split: bool) -> (f32, f32) {
+    match split {
+        true => (62.0, 32.0),
+        false => (94.0, 0.0),
+    }
+}
+
+/// Show or hide the error pane and move the `StdErr` marker to or from it.
+#[allow(clippy::type_complexity)]
+fn console_split_errors(
+    mut commands: Commands,
+    mut changed: EventReader<CVarChanged>,
+    query_output: Query<Entity, With<StdOut>>,
+    query_errors: Query<Entity, With<ErrorText>>,
+    mut query_panes: ParamSet<(
+        Query<&mut Style, With<OutputPane>>,
+        Query<&mut Style, With<ErrorPane>>,
+    )>,
+) {
+    for CVarChanged { name, value } in changed.read() {
+        let &CVarValue::Bool(split) = value else { continue };
+        if name != "console_split_errors" {
+            continue;
+        }
+        let (output_height, error_height) = pane_heights(split);
+        for mut style in &mut query_panes.p0() {
+            style.height = Val::Percent(output_height);
+        }
+        for mut style in &mut query_panes.p1() {
+            style.display = if split { Display::Flex } else { Display::None };
+            style.height = Val::Percent(error_height);
+        }
+        for errors in &query_errors {
+            match split {
+                true => commands.entity(errors).insert(StdErr),
+                false => commands.entity(errors).remove::<StdErr>(),
+            };
+        }
+        for output in &query_output {
+            match split {
+                true => commands.entity(output).remove::<StdErr>(),
+                false => commands.entity(output).insert(StdErr),
+            };
         }
     }
 }
 
-/// Add errors to UI.
-fn console_error(
+//------------------------------------------------------------------------------
+
+/// Add output and errors to UI in the order they were written, prefixing each error line.
+fn console_print(
+    mut stdout: EventReader<StdOutEvent>,
     mut stderr: EventReader<StdErrEvent>,
-    mut query: Query<&mut Text, With<StdErr>>,
+    mut query: Query<(&mut Text, &mut Streams, Has<StdOut>, Has<StdErr>)>,
     console: Res<ConsoleState>,
+    cvars: Res<CVars>,
 ) {
-    for event in stderr.read() {
-        for mut text in &mut query {
-            text.sections.push(TextSection::new(&event.value, console.style.clone()));
+    let show_errors = cvars.bool("console_show_errors").unwrap_or(true);
+    let prefix = cvars.string("console_error_prefix").unwrap_or_default();
+    let max = scrollback(&cvars);
+    for (stream, value) in read_ordered(&mut stdout, &mut stderr) {
+        let section = match stream {
+            Stream::Out => TextSection::new(value, console.style.clone()),
+            Stream::Err if !show_errors => continue,
+            Stream::Err => {
+                let value: String = value.split_inclusive('\n').map(|line| format!("{prefix}{line}")).collect();
+                TextSection::new(value, console.error_style.clone())
+            },
+        };
+        for (mut text, mut streams, out, err) in &mut query {
+            if !matches!((stream, out, err), (Stream::Out, true, _) | (Stream::Err, _, true)) {
+                continue;
+            }
+            text.sections.push(section.clone());
+            streams.0.push(stream);
+            trim_scrollback(&mut text.sections, &mut streams.0, max);
+        }
+    }
+}
+
+/// Maximum number of output lines.
+fn scrollback(cvars: &CVars) -> usize {
+    cvars.int("console_scrollback").unwrap_or(1000).max(0) as usize
+}
+
+/// Drop the oldest lines so at most `max` complete lines remain, with their streams.
+fn trim_scrollback(sections: &mut Vec<TextSection>, streams: &mut Vec<Stream>, max: usize) {
+    let lines: usize = sections.iter().map(|section| section.value.matches('\n').count()).sum();
+    let mut excess = lines.saturating_sub(max);
+    while excess > 0 {
+        let count = sections[0].value.matches('\n').count();
+        if count <= excess {
+            sections.remove(0);
+            streams.remove(0);
+            excess -= count;
+        } else {
+            let (end, _) = sections[0].value.match_indices('\n').nth(excess - 1).unwrap();
+            sections[0].value.drain(..=end);
+            excess = 0;
         }
     }
 }
 
-/// Scroll stdout with mouse wheel.
+/// Scroll output with the mouse wheel, PageUp/PageDown and Ctrl+Home/End.
+///
+/// The wheel scrolls the error pane while hovered and keys always scroll the
+/// output. Each pane follows new lines until scrolled up, scrolling back to the
+/// bottom follows again.
 fn console_scroll(
     mut mouse_wheel_events: EventReader<MouseWheel>,
-    mut console: ResMut<ConsoleState>,
-    mut query_list: Query<(&mut Style, &Parent, &Node), With<ConsoleScroll>>,
-    query_node: Query<&Node>,
+    keys: Res<ButtonInput<KeyCode>>,
+    open: Res<State<ConsoleOpen>>,
+    mut query_list: Query<(&mut ConsoleScroll, &mut Style, &Parent, &Node)>,
+    query_pane: Query<(&Node, &RelativeCursorPosition, Has<ErrorPane>)>,
 ) {
-    for mouse_wheel_event in mouse_wheel_events.read() {
-        for (mut style, parent, list_node) in &mut query_list {
-            // calculate maximum scroll
-            let items_height = list_node.size().y;
-            let container_height = query_node.get(parent.get()).unwrap().size().y;
-            let max_scroll = (items_height - container_height).max(0.0);
-
-            // scale different types of mouse scroll
-            let dy = match mouse_wheel_event.unit {
-                MouseScrollUnit::Line => mouse_wheel_event.y * 20.,
-                MouseScrollUnit::Pixel => mouse_wheel_event.y,
-            };
+    if open.is_changed() { // drop scrolling while closed
+        mouse_wheel_events.clear();
+    }
+
+    // scale different types of mouse scroll
+    let wheel: f32 = mouse_wheel_events.read().map(|event| match event.unit {
+        MouseScrollUnit::Line => event.y * 20.,
+        MouseScrollUnit::Pixel => event.y,
+    }).sum();
+    let control = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let errors_hovered = query_pane.iter().any(|(_, cursor, errors)| errors && cursor.mouse_over());
+
+    for (mut scroll, mut style, parent, list_node) in &mut query_list {
+        let Ok((pane_node, _, errors)) = query_pane.get(parent.get()) else { continue };
+
+        // calculate maximum scroll
+        let items_height = list_node.size().y;
+        let container_height = pane_node.size().y;
+        let max_scroll = (items_height - container_height).max(0.0);
+
+        // keys only scroll the output
+        let mut dy = if errors == errors_hovered { wheel } else { 0.0 };
+        if !errors {
+            if keys.just_pressed(KeyCode::PageUp) {
+                dy += container_height;
+            }
+            if keys.just_pressed(KeyCode::PageDown) {
+                dy -= container_height;
+            }
+            if control && keys.just_pressed(KeyCode::Home) {
+                dy = max_scroll;
+            }
+            if control && keys.just_pressed(KeyCode::End) {
+                dy = -max_scroll;
+            }
+        }
 
-            // scroll and clamp
-            console.position += dy;
-            console.position = console.position.clamp(-max_scroll, 0.0);
-            style.top = Val::Px(console.position);
+        // scroll and clamp, reaching the bottom follows output again
+        if dy != 0.0 {
+            scroll.position = (scroll.position + dy).clamp(-max_scroll, 0.0);
+            scroll.follow = scroll.position <= -max_scroll;
+        }
+        if scroll.follow {
+            scroll.position = -max_scroll;
         }
+        style.top = Val::Px(scroll.position);
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections(values: &[&str]) -> Vec<TextSection> {
+        values.iter().map(|value| TextSection::from(*value)).collect()
+    }
+
+    fn values(sections: &[TextSection]) -> Vec<&str> {
+        sections.iter().map(|section| section.value.as_str()).collect()
+    }
+
+    #[test]
+    fn scrollback() {
+        use Stream::*;
+        let mut text = sections(&["", "a\nb\n", "c\n", "d\ne\nf\n"]);
+        let mut streams = vec![Out, Out, Err, Out];
+        trim_scrollback(&mut text, &mut streams, 6);
+        assert_eq!(values(&text), ["", "a\nb\n", "c\n", "d\ne\nf\n"]);
+        trim_scrollback(&mut text, &mut streams, 5);
+        assert_eq!(values(&text), ["b\n", "c\n", "d\ne\nf\n"]);
+        assert_eq!(streams, [Out, Err, Out]);
+        trim_scrollback(&mut text, &mut streams, 2);
+        assert_eq!(values(&text), ["e\nf\n"]);
+        assert_eq!(streams, [Out]);
+        trim_scrollback(&mut text, &mut streams, 0);
+        assert!(text.is_empty() && streams.is_empty());
+    }
+
+    #[test]
+    fn scrollback_partial_line() {
+        let mut text = sections(&["a\nb\nprompt"]);
+        let mut streams = vec![Stream::Err];
+        trim_scrollback(&mut text, &mut streams, 1);
+        assert_eq!(values(&text), ["b\nprompt"]);
+        assert_eq!(streams, [Stream::Err]);
+    }
+
+    #[test]
+    fn ordered_streams() {
+        let mut world = World::new();
+        world.init_resource::<Events<StdOutEvent>>();
+        world.init_resource::<Events<StdErrEvent>>();
+        world.send_event(StdOutEvent::new(">load"));
+        world.send_event(StdErrEvent::new("load: not found"));
+        world.send_event(StdOutEvent::new(">help"));
+        world.send_event(StdErrEvent::new("usage: help"));
+
+        let mut read = IntoSystem::into_system(|mut stdout: EventReader<StdOutEvent>, mut stderr: EventReader<StdErrEvent>| {
+            read_ordered(&mut stdout, &mut stderr).into_iter()
+                .map(|(stream, value)| (stream, value.to_string()))
+                .collect::<Vec<_>>()
+        });
+        read.initialize(&mut world);
+        assert_eq!(read.run((), &mut world), [
+            (Stream::Out, ">load".into()),
+            (Stream::Err, "load: not found".into()),
+            (Stream::Out, ">help".into()),
+            (Stream::Err, "usage: help".into()),
+        ]);
+    }
+
+    /// Temporary script file, removed on drop.
+    struct Script(PathBuf);
+
+    impl Script {
+        fn new(name: &str, text: &str) -> Self {
+            let path = env::temp_dir().join(format!("wormhole-{}-{name}", std::process::id()));
+            fs::write(&path, text).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for Script {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Commands run by `record`, in order.
+    #[derive(Resource, Default)]
+    struct Recorded(Vec<Vec<String>>);
+
+    fn command_record(In(args): In<ConsoleArgs>, mut recorded: ResMut<Recorded>) {
+        recorded.0.push(args.args);
+    }
+
+    /// Run `exec` the way the console does, returning recorded commands and errors.
+    fn exec(path: &str) -> (Vec<Vec<String>>, Vec<String>) {
+        let mut app = App::new();
+        app.add_event::<StdOutEvent>();
+        app.add_event::<StdErrEvent>();
+        app.init_resource::<Recorded>();
+        app.register_console_command("exec", "", command_exec);
+        app.register_console_command("record", "", command_record);
+        let exec = app.world().resource::<CommandMap>().0["exec"].system;
+        app.world_mut().run_system_with_input(exec, ConsoleArgs::new(&["exec", path])).unwrap();
+
+        let errors = app.world_mut().resource_mut::<Events<StdErrEvent>>().drain().map(|event| event.value).collect();
+        (app.world_mut().remove_resource::<Recorded>().unwrap().0, errors)
+    }
+
+    #[test]
+    fn exec_nested() {
+        let inner = Script::new("inner.cfg", "record b\nrecord c; record d\n");
+        let outer = Script::new("outer.cfg", &format!("# setup\nrecord a\n\nexec {}\nrecord e\n", inner.path()));
+        let (recorded, errors) = exec(outer.path());
+        assert_eq!(recorded, [["a"], ["b"], ["c"], ["d"], ["e"]]);
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+
+    #[test]
+    fn exec_errors() {
+        let looping = Script::new("loop.cfg", "");
+        fs::write(&looping.0, format!("record a\nexec {}\nmissing\nrecord 'b\n", looping.path())).unwrap();
+        let (recorded, errors) = exec(looping.path());
+        assert_eq!(recorded, [["a"]]);
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].contains("recursive exec"));
+        assert_eq!(errors[1], "unknown command: missing\n");
+        assert!(errors[2].contains(":4: unterminated '"));
+
+        let (recorded, errors) = exec("/nonexistent/wormhole.cfg");
+        assert!(recorded.is_empty());
+        assert!(errors[0].starts_with("exec: /nonexistent/wormhole.cfg: "));
+    }
+
+    #[test]
+    fn complete_paths() {
+        let base = env::temp_dir().join(format!("wormhole-{}-data", std::process::id()));
+        fs::create_dir_all(base.join("Sub Dir")).unwrap();
+        for name in ["Fallout3.esm", "Mod.ESP", "Readme.txt", "Sub Dir/Patch.esp"] {
+            fs::write(base.join(name), "").unwrap();
+        }
+
+        let mut found = complete_path(&base, "", &["esm", "esp"]);
+        found.sort();
+        assert_eq!(found, ["Fallout3.esm", "Mod.ESP", "Sub Dir/"]);
+        assert_eq!(complete_path(&base, "Sub Dir/P", &["esp"]), ["Sub Dir/Patch.esp"]);
+        assert!(complete_path(&base, "Missing/", &["esp"]).is_empty());
+        fs::remove_dir_all(&base).unwrap();
     }
 }
@@ -1,6 +1,12 @@
 //! Curved CRT post process effect for Bevy.
+//!
+//! Parameters are console variables prefixed `crt_`. Presets are RON assets in
+//! `crt` in the asset root, applied with `crt preset name`.
+
+use std::{fmt, fs, io, path::PathBuf};
 
 use bevy::{
+    asset::{io::{file::FileAssetReader, Reader}, AssetLoader, AsyncReadExt, LoadContext, LoadState},
     core_pipeline::{
         core_2d::graph::{Core2d, Node2d},
         core_3d::graph::{Core3d, Node3d},
@@ -23,26 +29,47 @@ use bevy::{
         renderer::{RenderContext, RenderDevice},
         texture::BevyDefault,
         view::ViewTarget,
-        RenderApp,
+        Render, RenderApp, RenderSet,
     },
     ui::graph::NodeUi,
+    utils::HashMap,
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::console::{ConsoleArgs, ConsoleCommandExt, StdErrEvent, StdOutEvent};
+use crate::cvar::{CVarChanged, CVarExt, CVarValue, CVars};
+
 /// CRT post process effect plugin.
 pub struct ConsolePostProcessPlugin;
 
 impl Plugin for ConsolePostProcessPlugin {
     fn build(&self, app: &mut App) {
-        println!("ConsolePostProcessPlugin::build()");
+        info!("ConsolePostProcessPlugin::build()");
         app.add_plugins((
             ExtractComponentPlugin::<PostProcessSettings>::default(), // extract component from main world
             UniformComponentPlugin::<PostProcessSettings>::default(), // create uniform buffers for shader
         ));
 
-        app.add_systems(Update, update_settings);
+        let mut defaults = PostProcessSettings::default();
+        for (name, help) in PARAMETERS {
+            let default = *defaults.parameter_mut(name).unwrap();
+            app.register_cvar(&format!("crt_{name}"), default, help);
+        }
+        app.register_cvar("crt_phosphor", linear_color(defaults.phosphor), "CRT phosphor color for monochrome.");
+        app.init_asset::<CrtPreset>();
+        app.init_asset_loader::<CrtPresetLoader>();
+        app.init_resource::<PendingPreset>();
+        app.register_console_command("crt", "Apply a CRT look: crt preset name.", command_crt);
+        app.register_console_completer("crt", complete_crt);
+        app.add_systems(Update, (update_settings, settings_cvars, apply_preset));
 
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else { return; };
 
+        render_app
+            .init_resource::<PhosphorHistories>()
+            .add_systems(Render, prepare_phosphor_histories.in_set(RenderSet::PrepareResources));
+
         render_app // apply PostProcessNode to 2d and 3d
             .add_render_graph_node::<ViewNodeRunner<PostProcessNode>>(Core3d, PostProcessLabel)
             .add_render_graph_edges(Core3d, (
@@ -96,7 +123,7 @@ impl ViewNode for PostProcessNode {
     // to identify which camera(s) should run the effect.
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         (view_target, _post_process_settings, settings_index): QueryItem<Self::ViewQuery>,
         world: &World,
@@ -112,6 +139,11 @@ impl ViewNode for PostProcessNode {
         let Some(settings_binding) = settings_uniforms.uniforms().binding()
         else { return Ok(()); };
 
+        // previous frame is read while this frame is written
+        let Some(history) = world.resource::<PhosphorHistories>().0.get(&graph.view_entity())
+        else { return Ok(()); };
+        let (history_read, history_write) = history.views();
+
         // get source and destination textures
         let post_process = view_target.post_process_write();
 
@@ -130,17 +162,25 @@ impl ViewNode for PostProcessNode {
                 post_process.source, // source
                 &post_process_pipeline.sampler,
                 settings_binding.clone(),
+                history_read, // previous output
             )),
         );
 
         // Begin the render pass
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("post_process_pass"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: post_process.destination, // destination
-                resolve_target: None,
-                ops: Operations::default(),
-            })],
+            color_attachments: &[
+                Some(RenderPassColorAttachment {
+                    view: post_process.destination, // destination
+                    resolve_target: None,
+                    ops: Operations::default(),
+                }),
+                Some(RenderPassColorAttachment {
+                    view: history_write, // next frame's history
+                    resolve_target: None,
+                    ops: Operations::default(),
+                }),
+            ],
             depth_stencil_attachment: None,
             timestamp_writes: None,
             occlusion_query_set: None,
@@ -180,6 +220,7 @@ impl FromWorld for PostProcessPipeline {
                     texture_2d(TextureSampleType::Float { filterable: true }), // screen texture
                     sampler(SamplerBindingType::Filtering), // screen sampler
                     uniform_buffer::<PostProcessSettings>(true), // shader properties
+                    texture_2d(TextureSampleType::Float { filterable: true }), // phosphor history
                 ),
             ),
         );
@@ -204,11 +245,18 @@ impl FromWorld for PostProcessPipeline {
                     // Make sure this matches the entry point of your shader.
                     // It can be anything as long as it matches here and in the shader.
                     entry_point: "fragment".into(),
-                    targets: vec![Some(ColorTargetState {
-                        format: TextureFormat::bevy_default(),
-                        blend: None,
-                        write_mask: ColorWrites::ALL,
-                    })],
+                    targets: vec![
+                        Some(ColorTargetState {
+                            format: TextureFormat::bevy_default(),
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                        Some(ColorTargetState { // phosphor history
+                            format: HISTORY_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                    ],
                 }),
                 primitive: PrimitiveState::default(),
                 depth_stencil: None,
@@ -220,15 +268,392 @@ impl FromWorld for PostProcessPipeline {
     }
 }
 
-// shader properties
-#[derive(Component, Default, Clone, Copy, ExtractComponent, ShaderType)]
+const HISTORY_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// Last output of one view, blended into the next frame for phosphor trails.
+struct PhosphorHistory {
+    size: Extent3d,
+    views: [TextureView; 2], // Written on alternate frames.
+    frame: usize,
+}
+
+impl PhosphorHistory {
+    /// Two zeroed textures the size of the view.
+    fn new(render_device: &RenderDevice, size: Extent3d) -> Self {
+        let views = [0, 1].map(|_| render_device.create_texture(&TextureDescriptor {
+            label: Some("phosphor_history_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: HISTORY_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        }).create_view(&TextureViewDescriptor::default()));
+        Self { size, views, frame: 0 }
+    }
+
+    /// Texture to read and texture to write this frame.
+    fn views(&self) -> (&TextureView, &TextureView) {
+        (&self.views[self.frame % 2], &self.views[(self.frame + 1) % 2])
+    }
+}
+
+/// Phosphor history by view entity, kept across frames unlike view components.
+#[derive(Resource, Default)]
+struct PhosphorHistories(HashMap<Entity, PhosphorHistory>);
+
+/// Create, resize and flip history textures, dropping those of removed views.
+fn prepare_phosphor_histories(
+    render_device: Res<RenderDevice>,
+    mut histories: ResMut<PhosphorHistories>,
+    views: Query<(Entity, &ViewTarget), With<PostProcessSettings>>,
+) {
+    histories.0.retain(|entity, _| views.contains(*entity));
+    for (entity, view_target) in &views {
+        let size = view_target.main_texture().size();
+        match histories.0.get_mut(&entity) {
+            Some(history) if history.size == size => history.frame += 1,
+            _ => { histories.0.insert(entity, PhosphorHistory::new(&render_device, size)); },
+        }
+    }
+}
+
+// shader properties, missing fields take their defaults in presets
+#[derive(Component, Clone, Copy, ExtractComponent, ShaderType, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PostProcessSettings {
-    pub time: f32,
+    #[serde(skip)]
+    pub time: f32, // Seconds since startup.
+    pub curvature: Vec2, // Screen bulge, smaller is rounder.
+    #[serde(skip)]
+    pub target_size: Vec2, // Physical pixels rendered, from the camera.
+    pub lines: f32, // Virtual scanlines down, at most half the physical height.
+    pub scanline_opacity: f32, // Scanline darkening exponent.
+    pub vignette_opacity: f32, // Vignette darkening exponent.
+    pub vignette_roundness: f32, // Vignette falloff, larger reaches further in.
+    pub syncline_size: f32, // Rolling bar height, larger is thinner.
+    pub syncline_speed: f32, // Seconds for the rolling bar to cross the screen.
+    pub syncline_intensity: f32, // Rolling bar brightness.
+    pub brightness: f32, // Overall brightness.
+    pub warmup: f32, // Warm-up rate, larger reaches full brightness sooner.
+    pub phosphor: Vec3, // Linear color of monochrome output.
+    pub monochrome: f32, // Blend from full color to phosphor, 0 to 1.
+    pub persistence: f32, // Seconds for a phosphor trail to fade to half, 0 for none.
+    #[serde(skip)]
+    pub decay: f32, // Trail kept this frame, from persistence and frame time.
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            curvature: Vec2::splat(8.0),
+            target_size: Vec2::ZERO,
+            lines: 360.0,
+            scanline_opacity: 1.0,
+            vignette_opacity: 1.0,
+            vignette_roundness: 1.0,
+            syncline_size: 6.0,
+            syncline_speed: 5.0,
+            syncline_intensity: 0.5,
+            brightness: 3.0,
+            warmup: 1.0,
+            phosphor: Vec3::ONE,
+            monochrome: 0.0,
+            persistence: 0.0,
+            decay: 0.0,
+        }
+    }
+}
+
+/// Tunable parameters, exposed as console variables with a `crt_` prefix.
+const PARAMETERS: [(&str, &str); 13] = [
+    ("curvature_x", "CRT horizontal curvature, smaller is rounder."),
+    ("curvature_y", "CRT vertical curvature, smaller is rounder."),
+    ("lines", "CRT virtual scanlines down, limited to half the screen height."),
+    ("scanline_opacity", "CRT scanline darkening exponent."),
+    ("vignette_opacity", "CRT vignette darkening exponent."),
+    ("vignette_roundness", "CRT vignette falloff, larger reaches further in."),
+    ("syncline_size", "CRT rolling bar height, larger is thinner."),
+    ("syncline_speed", "CRT seconds for the rolling bar to cross the screen."),
+    ("syncline_intensity", "CRT rolling bar brightness."),
+    ("brightness", "CRT overall brightness."),
+    ("warmup", "CRT warm-up rate, larger reaches full brightness sooner."),
+    ("monochrome", "CRT blend from full color to phosphor, 0 to 1."),
+    ("persistence", "CRT seconds for a phosphor trail to fade to half, 0 for none."),
+];
+
+impl PostProcessSettings {
+    /// A tunable parameter by name, without the `crt_` prefix.
+    fn parameter_mut(&mut self, name: &str) -> Option<&mut f32> {
+        Some(match name {
+            "curvature_x" => &mut self.curvature.x,
+            "curvature_y" => &mut self.curvature.y,
+            "lines" => &mut self.lines,
+            "scanline_opacity" => &mut self.scanline_opacity,
+            "vignette_opacity" => &mut self.vignette_opacity,
+            "vignette_roundness" => &mut self.vignette_roundness,
+            "syncline_size" => &mut self.syncline_size,
+            "syncline_speed" => &mut self.syncline_speed,
+            "syncline_intensity" => &mut self.syncline_intensity,
+            "brightness" => &mut self.brightness,
+            "warmup" => &mut self.warmup,
+            "monochrome" => &mut self.monochrome,
+            "persistence" => &mut self.persistence,
+            _ => return None,
+        })
+    }
+
+    /// Take every parameter from its console variable.
+    pub fn with_cvars(mut self, cvars: &CVars) -> Self {
+        for (name, _) in PARAMETERS {
+            if let (Some(parameter), Some(value)) = (self.parameter_mut(name), cvars.float(&format!("crt_{name}"))) {
+                *parameter = value;
+            }
+        }
+        if let Some(color) = cvars.color("crt_phosphor") {
+            self.phosphor = linear_vec3(color);
+        }
+        self
+    }
+}
+
+/// Phosphor color as a color cvar.
+fn linear_color(phosphor: Vec3) -> Color {
+    LinearRgba::rgb(phosphor.x, phosphor.y, phosphor.z).into()
+}
+
+/// Color cvar as a phosphor color.
+fn linear_vec3(color: Color) -> Vec3 {
+    let color = color.to_linear();
+    Vec3::new(color.red, color.green, color.blue)
 }
 
-// provide time for temporal elements of shader
-fn update_settings(mut settings: Query<&mut PostProcessSettings>, time: Res<Time>) {
-    for mut setting in &mut settings {
+// provide time for temporal elements and the target size for scanlines
+fn update_settings(mut settings: Query<(&mut PostProcessSettings, &Camera)>, time: Res<Time>) {
+    for (mut setting, camera) in &mut settings {
         setting.time = time.elapsed_seconds();
+        setting.decay = match setting.persistence {
+            persistence if persistence > 0.0 => 0.5f32.powf(time.delta_seconds() / persistence),
+            _ => 0.0,
+        };
+        if let Some(size) = camera.physical_target_size() {
+            setting.target_size = size.as_vec2(); // follows resizes and scale factor changes
+        }
+    }
+}
+
+// apply changed console variables to every camera
+fn settings_cvars(mut changed: EventReader<CVarChanged>, mut settings: Query<&mut PostProcessSettings>) {
+    for event in changed.read() {
+        match (event.name.strip_prefix("crt_"), &event.value) {
+            (Some("phosphor"), &CVarValue::Color(color)) => {
+                let phosphor = linear_vec3(color);
+                for mut setting in &mut settings {
+                    setting.phosphor = phosphor;
+                }
+            },
+            (Some(name), &CVarValue::Float(value)) => {
+                for mut setting in &mut settings {
+                    if let Some(parameter) = setting.parameter_mut(name) {
+                        *parameter = value;
+                    }
+                }
+            },
+            _ => continue, // not a crt cvar, later events may be
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+const PRESETS: &str = "crt"; // Preset directory in the asset root.
+const PRESET_EXTENSION: &str = "crt.ron";
+
+/// A named CRT look, the tunable parameters of `PostProcessSettings`.
+#[derive(Asset, TypePath, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CrtPreset(pub PostProcessSettings);
+
+/// CRT preset load error.
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error), // Underlying reader failed.
+    Ron(ron::error::SpannedError), // Text isn't a valid preset.
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::Io(error) => write!(f, "{error}"),
+            PresetError::Ron(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<io::Error> for PresetError {
+    fn from(error: io::Error) -> Self {
+        PresetError::Io(error)
+    }
+}
+
+/// Loads CRT presets from RON.
+#[derive(Default)]
+struct CrtPresetLoader;
+
+impl AssetLoader for CrtPresetLoader {
+    type Asset = CrtPreset;
+    type Settings = ();
+    type Error = PresetError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<CrtPreset, PresetError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        ron::de::from_bytes(&bytes).map_err(PresetError::Ron)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[PRESET_EXTENSION]
+    }
+}
+
+/// Preset directory on disk, resolved like Bevy's file asset reader does.
+fn preset_dir() -> PathBuf {
+    FileAssetReader::new(AssetPlugin::default().file_path).root_path().join(PRESETS)
+}
+
+/// Whether a preset name stays inside the preset directory.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\']) && !name.contains("..")
+}
+
+/// Preset requested by `crt preset`, applied once loaded.
+#[derive(Resource, Default)]
+struct PendingPreset(Option<(String, Handle<CrtPreset>)>);
+
+/// Apply presets.
+fn command_crt(
+    In(args): In<ConsoleArgs>,
+    asset_server: Res<AssetServer>,
+    mut pending: ResMut<PendingPreset>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=2, "preset name") {
+        stderr.send(error);
+        return;
+    }
+    match (args.args.first().map(String::as_str), args.args.get(1)) {
+        (Some("preset"), Some(name)) if !valid_name(name) => {
+            stderr.send(StdErrEvent::new(format!("crt: {name}: invalid preset name\n")));
+        },
+        (Some("preset"), Some(name)) => {
+            let handle = asset_server.load(format!("{PRESETS}/{name}.{PRESET_EXTENSION}"));
+            pending.0 = Some((name.clone(), handle));
+        },
+        _ => {
+            let value = format!("usage: crt preset name\npresets: {}\n", preset_names().join(" "));
+            stdout.send(StdOutEvent::new(value));
+        },
+    }
+}
+
+/// Complete subcommands and preset names for `crt`.
+fn complete_crt(In(args): In<ConsoleArgs>) -> Vec<String> {
+    match args.args.len() {
+        1 => vec!["preset".into()],
+        2 if args[0] == *"preset" => preset_names(),
+        _ => Vec::new(),
+    }
+}
+
+/// Preset names in the preset directory, sorted.
+fn preset_names() -> Vec<String> {
+    let suffix = format!(".{PRESET_EXTENSION}");
+    let mut names: Vec<String> = fs::read_dir(preset_dir()).into_iter().flatten().flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(&suffix).map(String::from))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Copy a loaded preset into the console variables, which update every camera.
+fn apply_preset(
+    asset_server: Res<AssetServer>,
+    presets: Res<Assets<CrtPreset>>,
+    mut pending: ResMut<PendingPreset>,
+    mut cvars: ResMut<CVars>,
+    mut changed: EventWriter<CVarChanged>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    let Some((name, handle)) = &pending.0 else { return };
+    if let Some(LoadState::Failed(error)) = asset_server.get_load_state(handle) {
+        stderr.send(StdErrEvent::new(format!("crt: {name}: {error}\n")));
+        pending.0 = None;
+        return;
+    }
+    let Some(CrtPreset(preset)) = presets.get(handle) else { return };
+
+    let mut preset = *preset;
+    let mut values: Vec<(String, CVarValue)> = PARAMETERS.iter()
+        .map(|(parameter, _)| (format!("crt_{parameter}"), CVarValue::Float(*preset.parameter_mut(parameter).unwrap())))
+        .collect();
+    values.push(("crt_phosphor".into(), CVarValue::Color(linear_color(preset.phosphor))));
+    for (cvar, value) in values {
+        if cvars.assign(&cvar, value.clone()).is_ok() {
+            changed.send(CVarChanged { name: cvar, value });
+        }
+    }
+    stdout.send(StdOutEvent::new(format!("crt: {name}\n")));
+    pending.0 = None;
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let names = preset_names();
+        for name in ["amber", "off", "pipboy", "robco", "white"] {
+            assert!(names.contains(&name.to_string()), "missing preset {name}");
+        }
+        for name in names {
+            let text = fs::read_to_string(preset_dir().join(format!("{name}.{PRESET_EXTENSION}"))).unwrap();
+            let result: Result<CrtPreset, _> = ron::from_str(&text);
+            assert!(result.is_ok(), "{name}: {:?}", result.err());
+        }
+    }
+
+    #[test]
+    fn preset_name_validation() {
+        assert!(valid_name("pipboy") && valid_name("my-preset.v2"));
+        for name in ["", "../main", "crt/pipboy", "..\\pipboy", "a..b", "/etc/passwd"] {
+            assert!(!valid_name(name), "{name:?} accepted");
+        }
+    }
+
+    #[test]
+    fn preset_defaults() {
+        let CrtPreset(settings) = ron::from_str("(lines: 240.0)").unwrap();
+        assert_eq!(settings.lines, 240.0);
+        assert_eq!(settings.curvature, PostProcessSettings::default().curvature);
+        assert_eq!(settings.persistence, 0.0); // no trails unless a preset asks for them
+
+        let text = ron::ser::to_string(&CrtPreset(settings)).unwrap();
+        assert!(!text.contains("time") && !text.contains("target_size"));
+        let CrtPreset(round_trip) = ron::from_str(&text).unwrap();
+        assert_eq!(round_trip.phosphor, settings.phosphor);
     }
 }
@@ -0,0 +1,350 @@
+//! Console variables, typed runtime settings declared by plugins.
+//!
+//! Plugins register variables with `App::register_cvar`, the console changes
+//! them with `set` and systems react to `CVarChanged` events.
+
+use std::{collections::BTreeMap, fmt};
+
+use bevy::prelude::*;
+
+use crate::console::{ConsoleArgs, ConsoleCommandExt, StdErrEvent, StdOutEvent};
+
+/// Console variable commands.
+pub struct CVarPlugin;
+
+impl Plugin for CVarPlugin {
+    fn build(&self, app: &mut App) {
+        info!("CVarPlugin::build()");
+        app.init_resource::<CVars>();
+        app.add_event::<CVarChanged>();
+        app.register_console_command("cvars", "List console variables matching a filter: cvars [filter].", command_cvars);
+        app.register_console_command("get", "Print a console variable: get name.", command_get);
+        app.register_console_completer("get", complete_name);
+        app.register_console_command("set", "Change a console variable: set name value.", command_set);
+        app.register_console_completer("set", complete_name);
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// A console variable value, its type is fixed by the default.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CVarValue {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    String(String),
+    Color(Color), // Written as sRGB hex, e.g. #29E18C.
+}
+
+impl CVarValue {
+    /// Type name for messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CVarValue::Bool(_) => "bool",
+            CVarValue::Int(_) => "int",
+            CVarValue::Float(_) => "float",
+            CVarValue::String(_) => "string",
+            CVarValue::Color(_) => "color",
+        }
+    }
+
+    /// Parse text as a value of the same type.
+    fn parse_as(&self, text: &str) -> Option<Self> {
+        match self {
+            CVarValue::Bool(_) => match text.to_lowercase().as_str() {
+                "1" | "true" | "on" | "yes" => Some(CVarValue::Bool(true)),
+                "0" | "false" | "off" | "no" => Some(CVarValue::Bool(false)),
+                _ => None,
+            },
+            CVarValue::Int(_) => text.parse().ok().map(CVarValue::Int),
+            CVarValue::Float(_) => text.parse().ok().filter(|value: &f32| value.is_finite()).map(CVarValue::Float),
+            CVarValue::String(_) => Some(CVarValue::String(text.into())),
+            CVarValue::Color(_) => Srgba::hex(text).ok().map(|color| CVarValue::Color(color.into())),
+        }
+    }
+}
+
+impl fmt::Display for CVarValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CVarValue::Bool(value) => write!(f, "{value}"),
+            CVarValue::Int(value) => write!(f, "{value}"),
+            CVarValue::Float(value) => write!(f, "{value}"),
+            CVarValue::String(value) => write!(f, "{value:?}"),
+            CVarValue::Color(value) => write!(f, "{}", value.to_srgba().to_hex()),
+        }
+    }
+}
+
+impl From<bool> for CVarValue {
+    fn from(value: bool) -> Self {
+        CVarValue::Bool(value)
+    }
+}
+
+impl From<i64> for CVarValue {
+    fn from(value: i64) -> Self {
+        CVarValue::Int(value)
+    }
+}
+
+impl From<f32> for CVarValue {
+    fn from(value: f32) -> Self {
+        CVarValue::Float(value)
+    }
+}
+
+impl From<&str> for CVarValue {
+    fn from(value: &str) -> Self {
+        CVarValue::String(value.into())
+    }
+}
+
+impl From<Color> for CVarValue {
+    fn from(value: Color) -> Self {
+        CVarValue::Color(value)
+    }
+}
+
+/// Console variable error.
+#[derive(Debug)]
+pub enum CVarError {
+    Unknown(String), // No variable with this name.
+    Invalid(String, &'static str, String), // Name, expected type and the rejected text.
+}
+
+impl fmt::Display for CVarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CVarError::Unknown(name) => write!(f, "{name}: no such variable"),
+            CVarError::Invalid(name, kind, text) => write!(f, "{name}: invalid {kind}: {text}"),
+        }
+    }
+}
+
+/// A registered console variable.
+#[derive(Debug, Clone)]
+pub struct CVar {
+    pub value: CVarValue,
+    pub default: CVarValue,
+    pub help: String, // One line description.
+}
+
+/// Registered console variables by name.
+#[derive(Resource, Default)]
+pub struct CVars(BTreeMap<String, CVar>);
+
+impl CVars {
+    /// Current value of a variable.
+    pub fn get(&self, name: &str) -> Option<&CVarValue> {
+        self.0.get(name).map(|cvar| &cvar.value)
+    }
+
+    /// Current value of a bool variable.
+    pub fn bool(&self, name: &str) -> Option<bool> {
+        match self.get(name)? {
+            CVarValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Current value of an int variable.
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.get(name)? {
+            CVarValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Current value of a float variable.
+    pub fn float(&self, name: &str) -> Option<f32> {
+        match self.get(name)? {
+            CVarValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Current value of a string variable.
+    pub fn string(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            CVarValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Current value of a color variable.
+    pub fn color(&self, name: &str) -> Option<Color> {
+        match self.get(name)? {
+            CVarValue::Color(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Parse and assign a variable, returning the new value.
+    pub fn set(&mut self, name: &str, text: &str) -> Result<&CVarValue, CVarError> {
+        let cvar = self.0.get_mut(name).ok_or_else(|| CVarError::Unknown(name.into()))?;
+        cvar.value = cvar.value.parse_as(text)
+            .ok_or_else(|| CVarError::Invalid(name.into(), cvar.value.kind(), text.into()))?;
+        Ok(&cvar.value)
+    }
+
+    /// Assign a value of the variable's type.
+    pub fn assign(&mut self, name: &str, value: CVarValue) -> Result<(), CVarError> {
+        let cvar = self.0.get_mut(name).ok_or_else(|| CVarError::Unknown(name.into()))?;
+        let finite = !matches!(value, CVarValue::Float(value) if !value.is_finite());
+        if cvar.value.kind() != value.kind() || !finite {
+            return Err(CVarError::Invalid(name.into(), cvar.value.kind(), value.to_string()));
+        }
+        cvar.value = value;
+        Ok(())
+    }
+
+    /// Variables in name order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &CVar)> {
+        self.0.iter()
+    }
+}
+
+/// Sent after a console variable changes.
+#[derive(Event, Debug, Clone)]
+pub struct CVarChanged {
+    pub name: String,
+    pub value: CVarValue,
+}
+
+/// Declares console variables on the app, for use by any plugin.
+pub trait CVarExt {
+    /// Add a variable with its default value and a one line description.
+    fn register_cvar(&mut self, name: &str, default: impl Into<CVarValue>, help: &str) -> &mut Self;
+}
+
+impl CVarExt for App {
+    fn register_cvar(&mut self, name: &str, default: impl Into<CVarValue>, help: &str) -> &mut Self {
+        let default = default.into();
+        let cvar = CVar { value: default.clone(), default, help: help.into() };
+        self.world_mut().get_resource_or_insert_with(CVars::default).0.insert(name.into(), cvar);
+        self
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Change a variable and notify listeners.
+fn command_set(
+    In(args): In<ConsoleArgs>,
+    mut cvars: ResMut<CVars>,
+    mut changed: EventWriter<CVarChanged>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(2..=2, "name value") {
+        stderr.send(error);
+        return;
+    }
+    match cvars.set(&args[0], &args[1]) {
+        Ok(value) => {
+            stdout.send(StdOutEvent::new(format!("{} = {value}\n", &args[0])));
+            changed.send(CVarChanged { name: args[0].into(), value: value.clone() });
+        },
+        Err(error) => { stderr.send(StdErrEvent::new(format!("set: {error}\n"))); },
+    }
+}
+
+/// Print a variable.
+fn command_get(
+    In(args): In<ConsoleArgs>,
+    cvars: Res<CVars>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(1..=1, "name") {
+        stderr.send(error);
+        return;
+    }
+    match cvars.get(&args[0]) {
+        Some(value) => { stdout.send(StdOutEvent::new(format!("{} = {value}\n", &args[0]))); },
+        None => { stderr.send(StdErrEvent::new(format!("get: {}\n", CVarError::Unknown(args[0].into())))); },
+    }
+}
+
+/// List variables whose name contains the filter.
+fn command_cvars(
+    In(args): In<ConsoleArgs>,
+    cvars: Res<CVars>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=1, "[filter]") {
+        stderr.send(error);
+        return;
+    }
+    let filter = args.args.first().map(|filter| filter.to_lowercase()).unwrap_or_default();
+    let mut value = String::new();
+    for (name, cvar) in cvars.iter().filter(|(name, _)| name.contains(&filter)) {
+        value.push_str(&format!("{name:<23} {:<11} {} ({}, default {})\n", cvar.value.to_string(), cvar.help, cvar.value.kind(), cvar.default));
+    }
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Complete variable names for `get` and `set`.
+fn complete_name(In(args): In<ConsoleArgs>, cvars: Res<CVars>) -> Vec<String> {
+    match args.args.len() {
+        1 => cvars.iter().map(|(name, _)| name.clone()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cvars() -> CVars {
+        let mut app = App::new();
+        app.register_cvar("show", true, "A bool.")
+            .register_cvar("lines", 10i64, "An int.")
+            .register_cvar("size", 16.0, "A float.")
+            .register_cvar("prefix", "", "A string.")
+            .register_cvar("color", Color::BLACK, "A color.");
+        app.world_mut().remove_resource::<CVars>().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let mut cvars = cvars();
+        for (text, value) in [("on", true), ("FALSE", false), ("1", true), ("no", false)] {
+            assert_eq!(cvars.set("show", text).unwrap(), &CVarValue::Bool(value));
+        }
+        assert_eq!(cvars.set("lines", "-3").unwrap(), &CVarValue::Int(-3));
+        assert_eq!(cvars.set("size", "12.5").unwrap(), &CVarValue::Float(12.5));
+        assert_eq!(cvars.set("prefix", "> ").unwrap(), &CVarValue::String("> ".into()));
+        assert_eq!(cvars.set("color", "#29E18C").unwrap(), &CVarValue::Color(Color::srgb_u8(0x29, 0xE1, 0x8C)));
+        assert_eq!(cvars.set("color", "29e18c").unwrap().to_string(), "#29E18C");
+        assert_eq!(cvars.int("lines"), Some(-3));
+        assert_eq!(cvars.float("lines"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut cvars = cvars();
+        for (name, text) in [("show", "maybe"), ("lines", "1.5"), ("size", "big"), ("color", "#12345")] {
+            assert!(matches!(cvars.set(name, text), Err(CVarError::Invalid(..))), "{name} {text}");
+        }
+        for text in ["NaN", "inf", "-infinity"] {
+            assert!(matches!(cvars.set("size", text), Err(CVarError::Invalid(_, "float", _))), "{text}");
+        }
+        assert_eq!(cvars.float("size"), Some(16.0));
+        assert!(matches!(cvars.set("missing", "1"), Err(CVarError::Unknown(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn assign() {
+        let mut cvars = cvars();
+        cvars.assign("size", CVarValue::Float(8.0)).unwrap();
+        assert_eq!(cvars.float("size"), Some(8.0));
+        assert!(matches!(cvars.assign("size", CVarValue::Int(8)), Err(CVarError::Invalid(_, "float", _))));
+        assert!(matches!(cvars.assign("size", CVarValue::Float(f32::NAN)), Err(CVarError::Invalid(..))));
+        assert!(matches!(cvars.assign("missing", true.into()), Err(CVarError::Unknown(_))));
+        assert_eq!(cvars.float("size"), Some(8.0));
+    }
+}
@@ -0,0 +1,212 @@
+//! Game data asset source, resolving paths like the engine's data directory.
+//!
+//! Loose files take priority over archives, and archives mounted later take
+//! priority over those mounted earlier. Assets are addressed as
+//! `bsa://textures/clutter/food/beerbottle.dds`.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use blocking::unblock;
+
+use bevy::{
+    asset::io::{
+        file::FileAssetReader, AssetReader, AssetReaderError, AssetSource, PathStream, Reader,
+        VecReader,
+    },
+    prelude::*,
+    tasks::futures_lite::{stream, StreamExt},
+};
+
+use crate::bsa::{self, Archive};
+use crate::console::{complete_path, ConsoleArgs, ConsoleCommandExt, StdErrEvent, StdOutEvent};
+
+/// Asset source name.
+pub const SOURCE: &str = "bsa";
+
+/// Registers the data asset source, must be added before `AssetPlugin`.
+pub struct DataSourcePlugin {
+    pub loose: PathBuf, // Loose file directory, relative to the asset base path.
+}
+
+impl Default for DataSourcePlugin {
+    fn default() -> Self {
+        Self { loose: "assets".into() }
+    }
+}
+
+impl Plugin for DataSourcePlugin {
+    fn build(&self, app: &mut App) {
+        info!("DataSourcePlugin::build()");
+        let archives = Archives::default();
+        let loose = self.loose.clone();
+        let shared = archives.clone();
+        app.register_asset_source(SOURCE, AssetSource::build().with_reader(move || {
+            Box::new(DataReader { loose: FileAssetReader::new(&loose), archives: shared.clone() })
+        }));
+        app.insert_resource(archives);
+        app.init_resource::<DataDirectory>();
+        app.register_console_command("bsa", "List files in a BSA archive: bsa ls archive [glob].", command_bsa);
+        app.register_console_completer("bsa", complete_bsa);
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Game data directory, relative plugin and archive paths given to commands are resolved against it.
+#[derive(Resource, Clone, Default)]
+pub struct DataDirectory(pub PathBuf);
+
+/// Archives mounted in the data source, shared with its readers.
+#[derive(Resource, Clone, Default)]
+pub struct Archives(Arc<RwLock<Vec<Archive>>>);
+
+impl Archives {
+    /// Read an archive's index and add it above those already mounted.
+    pub fn mount(&self, path: impl AsRef<Path>) -> Result<(), bsa::Error> {
+        let archive = Archive::open(path)?;
+        self.0.write().unwrap().push(archive);
+        Ok(())
+    }
+
+    /// Read a file from the highest priority archive containing it.
+    fn read(&self, path: &Path) -> Option<Result<Vec<u8>, bsa::Error>> {
+        let path = path.to_string_lossy();
+        let archives = self.0.read().unwrap();
+        let archive = archives.iter().rev().find(|archive| archive.find(&path).is_some())?;
+        Some(archive.read(&path))
+    }
+
+    /// Whether any archive holds the folder.
+    fn is_directory(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.0.read().unwrap().iter().any(|archive| archive.is_directory(&path))
+    }
+
+    /// Entries directly inside a folder across all archives.
+    fn read_directory(&self, path: &Path) -> Vec<PathBuf> {
+        let folder = bsa::normalize(&path.to_string_lossy());
+        let mut entries = Vec::new();
+        for archive in self.0.read().unwrap().iter() {
+            for (name, _) in archive.files() {
+                let Some(rest) = name.strip_prefix(&folder) else { continue; };
+                let rest = if folder.is_empty() { rest } else {
+                    let Some(rest) = rest.strip_prefix('\\') else { continue; };
+                    rest
+                };
+                // files, or the first component of deeper folders
+                let entry = rest.split('\\').next().unwrap_or(rest);
+                entries.push(path.join(entry));
+            }
+        }
+        entries.sort();
+        entries.dedup();
+        entries
+    }
+}
+
+/// Layers mounted archives beneath a loose file directory.
+struct DataReader {
+    loose: FileAssetReader,
+    archives: Archives,
+}
+
+impl AssetReader for DataReader {
+    async fn read<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        match self.loose.read(path).await {
+            Err(AssetReaderError::NotFound(_)) => {},
+            result => return result,
+        }
+        // archive reads open, seek and inflate synchronously, keep them off the async executor
+        let (archives, owned) = (self.archives.clone(), path.to_path_buf());
+        match unblock(move || archives.read(&owned)).await {
+            Some(Ok(bytes)) => Ok(Box::new(VecReader::new(bytes))),
+            Some(Err(error)) => Err(archive_error(error)),
+            None => Err(AssetReaderError::NotFound(path.to_path_buf())),
+        }
+    }
+
+    async fn read_meta<'a>(&'a self, path: &'a Path) -> Result<Box<Reader<'a>>, AssetReaderError> {
+        // archives have no meta files, fall back to loader defaults
+        self.loose.read_meta(path).await
+    }
+
+    async fn read_directory<'a>(&'a self, path: &'a Path) -> Result<Box<PathStream>, AssetReaderError> {
+        let mut entries = self.archives.read_directory(path);
+        match self.loose.read_directory(path).await {
+            Ok(loose) => entries.extend(loose.collect::<Vec<_>>().await),
+            Err(AssetReaderError::NotFound(_)) if !entries.is_empty() => {},
+            Err(error) => return Err(error),
+        }
+        entries.sort();
+        entries.dedup();
+        Ok(Box::new(stream::iter(entries)))
+    }
+
+    async fn is_directory<'a>(&'a self, path: &'a Path) -> Result<bool, AssetReaderError> {
+        let (archives, owned) = (self.archives.clone(), path.to_path_buf());
+        if unblock(move || archives.is_directory(&owned)).await {
+            return Ok(true);
+        }
+        self.loose.is_directory(path).await
+    }
+}
+
+/// Convert an archive error for the asset server.
+fn archive_error(error: bsa::Error) -> AssetReaderError {
+    match error {
+        bsa::Error::Io(error) => AssetReaderError::Io(Arc::new(error)),
+        error => AssetReaderError::Io(Arc::new(io::Error::new(io::ErrorKind::InvalidData, error.to_string()))),
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Browse BSA archives.
+pub fn command_bsa(
+    In(args): In<ConsoleArgs>,
+    data: Res<DataDirectory>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(2..=3, "ls archive [glob]") {
+        stderr.send(error);
+        return;
+    }
+    if &args[0] != "ls" {
+        stderr.send(StdErrEvent::new(format!("bsa: unknown subcommand: {}\n", &args[0])));
+        return;
+    }
+
+    let path = &args[1];
+    let archive = match bsa::Archive::open(data.0.join(path)) {
+        Ok(archive) => archive,
+        Err(error) => {
+            stderr.send(StdErrEvent::new(format!("bsa: {path}: {error}\n")));
+            return;
+        }
+    };
+
+    let pattern = args.args.get(2).map_or("*", String::as_str);
+    let mut value = String::new();
+    let mut count = 0;
+    for (name, file) in archive.files().filter(|(name, _)| bsa::glob(pattern, name)) {
+        let compressed = if file.compressed { "z" } else { " " };
+        value.push_str(&format!("{:>10} {compressed} {name}\n", file.size));
+        count += 1;
+    }
+    value.push_str(&format!("{count} of {} files\n", archive.header.file_count));
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Complete the subcommand and archive path for `bsa`.
+pub fn complete_bsa(In(args): In<ConsoleArgs>, data: Res<DataDirectory>) -> Vec<String> {
+    match args.args.len() {
+        1 => vec!["ls".into()],
+        2 => complete_path(&data.0, &args[1], &["bsa"]),
+        _ => Vec::new(),
+    }
+}
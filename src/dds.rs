@@ -0,0 +1,515 @@
+//! DirectDraw Surface (DDS) texture parsing and loading.
+//!
+//! Game textures are DXT1, DXT3 or DXT5 (BC1, BC2, BC3) with a full mip chain,
+//! occasionally uncompressed 24 or 32 bit. Compressed data is passed straight
+//! to the GPU when it supports BC formats, otherwise it is decoded to RGBA8.
+
+use std::{fmt, io};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        renderer::RenderDevice,
+        texture::{CompressedImageFormats, ImageSampler},
+    },
+};
+use serde::{Deserialize, Serialize};
+
+/// Size of the magic and header.
+const HEADER_SIZE: usize = 128;
+
+/// Header flag, mip map count is valid.
+const DDSD_MIPMAPCOUNT: u32 = 0x0002_0000;
+/// Pixel format flag, alpha mask is valid.
+const DDPF_ALPHAPIXELS: u32 = 0x0001;
+/// Pixel format flag, four character code is valid.
+const DDPF_FOURCC: u32 = 0x0004;
+/// Pixel format flag, uncompressed RGB masks are valid.
+const DDPF_RGB: u32 = 0x0040;
+
+/// DDS parse error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error), // Underlying reader failed.
+    Magic([u8; 4]), // File doesn't start with "DDS ".
+    Format(String), // Pixel format isn't supported.
+    Size(u32, u32), // Width or height is zero.
+    Truncated(&'static str), // Data ended inside the named structure.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Magic(magic) => write!(f, "not a dds file (found {:?})", String::from_utf8_lossy(magic)),
+            Error::Format(format) => write!(f, "unsupported pixel format {format}"),
+            Error::Size(width, height) => write!(f, "invalid size {width}x{height}"),
+            Error::Truncated(what) => write!(f, "truncated {what}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Supported pixel formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Dxt1, // BC1, 8 bytes per 4x4 block, optional 1 bit alpha.
+    Dxt3, // BC2, 16 bytes per block, explicit 4 bit alpha.
+    Dxt5, // BC3, 16 bytes per block, interpolated alpha.
+    Rgb { bits: u32, masks: [u32; 4] }, // Uncompressed, 24 or 32 bits with RGBA masks.
+}
+
+impl Format {
+    /// Bytes in one mip level.
+    pub fn level_size(self, width: u32, height: u32) -> usize {
+        let blocks = |size: u32| size.div_ceil(4).max(1) as usize;
+        match self {
+            Format::Dxt1 => blocks(width) * blocks(height) * 8,
+            Format::Dxt3 | Format::Dxt5 => blocks(width) * blocks(height) * 16,
+            Format::Rgb { bits, .. } => width as usize * height as usize * bits as usize / 8,
+        }
+    }
+}
+
+/// A parsed texture.
+#[derive(Debug, Clone)]
+pub struct Dds {
+    pub width: u32,
+    pub height: u32,
+    pub mip_levels: u32, // Levels actually present in `data`.
+    pub format: Format,
+    pub data: Vec<u8>, // Every mip level, largest first.
+}
+
+impl Dds {
+    /// Parse a texture held in memory.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let Some(header) = bytes.get(..HEADER_SIZE) else {
+            return Err(Error::Truncated("header"));
+        };
+        let magic = [header[0], header[1], header[2], header[3]];
+        if magic != *b"DDS " {
+            return Err(Error::Magic(magic));
+        }
+        let field = |index: usize| u32_at(header, 4 + index * 4);
+        let flags = field(1);
+        let (height, width) = (field(2), field(3));
+        if width == 0 || height == 0 {
+            return Err(Error::Size(width, height));
+        }
+        // never more levels than the full chain down to 1x1
+        let chain = 32 - width.max(height).leading_zeros();
+        let mip_count = if flags & DDSD_MIPMAPCOUNT != 0 { field(6).clamp(1, chain) } else { 1 };
+
+        // pixel format starts at field 18
+        let pixel_flags = field(19);
+        let format = if pixel_flags & DDPF_FOURCC != 0 {
+            match &header[84..88] {
+                b"DXT1" => Format::Dxt1,
+                b"DXT2" | b"DXT3" => Format::Dxt3,
+                b"DXT4" | b"DXT5" => Format::Dxt5,
+                fourcc => return Err(Error::Format(String::from_utf8_lossy(fourcc).into())),
+            }
+        } else if pixel_flags & DDPF_RGB != 0 && matches!(field(21), 24 | 32) {
+            let alpha = if pixel_flags & DDPF_ALPHAPIXELS != 0 { field(25) } else { 0 };
+            Format::Rgb { bits: field(21), masks: [field(22), field(23), field(24), alpha] }
+        } else {
+            return Err(Error::Format(format!("flags {pixel_flags:#x}")));
+        };
+
+        // keep as many mip levels as are present
+        let mut data = &bytes[HEADER_SIZE..];
+        let mut size = 0;
+        let mut mip_levels = 0;
+        for level in 0..mip_count {
+            let level_size = format.level_size((width >> level).max(1), (height >> level).max(1));
+            if size + level_size > data.len() {
+                break;
+            }
+            size += level_size;
+            mip_levels += 1;
+        }
+        if mip_levels == 0 {
+            return Err(Error::Truncated("image data"));
+        }
+        data = &data[..size];
+
+        Ok(Dds { width, height, mip_levels, format, data: data.to_vec() })
+    }
+
+    /// Whether the format is block compressed.
+    pub fn is_compressed(&self) -> bool {
+        !matches!(self.format, Format::Rgb { .. })
+    }
+
+    /// Decode every mip level to RGBA8.
+    pub fn decode_rgba8(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut data = &self.data[..];
+        for level in 0..self.mip_levels {
+            let (width, height) = ((self.width >> level).max(1), (self.height >> level).max(1));
+            let size = self.format.level_size(width, height);
+            output.extend(decode_level(self.format, &data[..size], width, height));
+            data = &data[size..];
+        }
+        output
+    }
+}
+
+/// Decode a single mip level to RGBA8.
+fn decode_level(format: Format, data: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut output = vec![0u8; width * height * 4];
+
+    let Format::Rgb { bits, masks } = format else {
+        let block_size = if format == Format::Dxt1 { 8 } else { 16 };
+        let blocks_wide = width.div_ceil(4);
+        for (index, block) in data.chunks_exact(block_size).enumerate() {
+            let pixels = match format {
+                Format::Dxt1 => decode_bc1(block.try_into().unwrap(), true),
+                Format::Dxt3 => decode_bc2(block.try_into().unwrap()),
+                _ => decode_bc3(block.try_into().unwrap()),
+            };
+            let (bx, by) = (index % blocks_wide * 4, index / blocks_wide * 4);
+            for (pixel, rgba) in pixels.iter().enumerate() {
+                let (x, y) = (bx + pixel % 4, by + pixel / 4);
+                if x < width && y < height {
+                    let offset = (y * width + x) * 4;
+                    output[offset..offset + 4].copy_from_slice(rgba);
+                }
+            }
+        }
+        return output;
+    };
+
+    let stride = bits as usize / 8;
+    for (pixel, rgba) in data.chunks_exact(stride).zip(output.chunks_exact_mut(4)) {
+        let mut value = [0u8; 4];
+        value[..stride].copy_from_slice(pixel);
+        let value = u32::from_le_bytes(value);
+        for (channel, &mask) in rgba.iter_mut().zip(&masks) {
+            *channel = if mask == 0 { 255 } else { ((value & mask) >> mask.trailing_zeros()) as u8 };
+        }
+    }
+    output
+}
+
+/// Expand RGB565 to RGB888.
+fn rgb565(color: u16) -> [u8; 3] {
+    let (r, g, b) = ((color >> 11) & 31, (color >> 5) & 63, color & 31);
+    [(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8]
+}
+
+/// Decode a BC1 colour block, `punch_through` enables the 3 colour and transparent mode.
+pub fn decode_bc1(block: &[u8; 8], punch_through: bool) -> [[u8; 4]; 16] {
+    let (c0, c1) = (u16::from_le_bytes([block[0], block[1]]), u16::from_le_bytes([block[2], block[3]]));
+    let (a, b) = (rgb565(c0), rgb565(c1));
+    let mix = |wa: u16, wb: u16| -> [u8; 4] {
+        let channel = |i: usize| ((a[i] as u16 * wa + b[i] as u16 * wb) / (wa + wb)) as u8;
+        [channel(0), channel(1), channel(2), 255]
+    };
+
+    let palette = if c0 > c1 || !punch_through {
+        [[a[0], a[1], a[2], 255], [b[0], b[1], b[2], 255], mix(2, 1), mix(1, 2)]
+    } else {
+        [[a[0], a[1], a[2], 255], [b[0], b[1], b[2], 255], mix(1, 1), [0, 0, 0, 0]]
+    };
+
+    let indices = u32_at(block, 4);
+    std::array::from_fn(|pixel| palette[(indices >> (pixel * 2) & 3) as usize])
+}
+
+/// Decode a BC2 block, explicit alpha followed by colour.
+pub fn decode_bc2(block: &[u8; 16]) -> [[u8; 4]; 16] {
+    let mut pixels = decode_bc1(block[8..].try_into().unwrap(), false);
+    let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+    for (pixel, rgba) in pixels.iter_mut().enumerate() {
+        let value = (alpha >> (pixel * 4) & 15) as u8;
+        rgba[3] = value << 4 | value;
+    }
+    pixels
+}
+
+/// Decode a BC3 block, interpolated alpha followed by colour.
+pub fn decode_bc3(block: &[u8; 16]) -> [[u8; 4]; 16] {
+    let mut pixels = decode_bc1(block[8..].try_into().unwrap(), false);
+    let (a0, a1) = (block[0] as u16, block[1] as u16);
+    let palette: [u8; 8] = std::array::from_fn(|index| match index as u16 {
+        0 => a0 as u8,
+        1 => a1 as u8,
+        i if a0 > a1 => ((a0 * (8 - i) + a1 * (i - 1)) / 7) as u8,
+        i @ 2..=5 => ((a0 * (6 - i) + a1 * (i - 1)) / 5) as u8,
+        6 => 0,
+        _ => 255,
+    });
+    let mut indices = [0u8; 8];
+    indices[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(indices);
+    for (pixel, rgba) in pixels.iter_mut().enumerate() {
+        rgba[3] = palette[(indices >> (pixel * 3) & 7) as usize];
+    }
+    pixels
+}
+
+/// Little endian u32 at offset, caller guarantees bounds.
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+//------------------------------------------------------------------------------
+
+/// Texture load settings.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DdsSettings {
+    pub is_srgb: bool, // Colour data, false for normal maps.
+}
+
+impl Default for DdsSettings {
+    fn default() -> Self {
+        Self { is_srgb: true }
+    }
+}
+
+/// Loads DDS textures, decoding on the CPU when BC formats are unavailable.
+pub struct DdsLoader {
+    supported: CompressedImageFormats,
+}
+
+impl FromWorld for DdsLoader {
+    fn from_world(world: &mut World) -> Self {
+        let supported = match world.get_resource::<RenderDevice>() {
+            Some(render_device) => CompressedImageFormats::from_features(render_device.features()),
+            None => CompressedImageFormats::NONE,
+        };
+        Self { supported }
+    }
+}
+
+impl AssetLoader for DdsLoader {
+    type Asset = Image;
+    type Settings = DdsSettings;
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        settings: &'a DdsSettings,
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Image, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let dds = Dds::parse(&bytes)?;
+        Ok(self.image(dds, settings.is_srgb))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["dds"]
+    }
+}
+
+impl DdsLoader {
+    /// Build an image, passing compressed data through when the GPU supports it.
+    fn image(&self, dds: Dds, is_srgb: bool) -> Image {
+        // the GPU requires whole blocks on the top level
+        let aligned = dds.width.is_multiple_of(4) && dds.height.is_multiple_of(4);
+        let passthrough = dds.is_compressed() && aligned && self.supported.contains(CompressedImageFormats::BC);
+
+        let (format, data) = match (passthrough, dds.format, is_srgb) {
+            (true, Format::Dxt1, true) => (TextureFormat::Bc1RgbaUnormSrgb, dds.data),
+            (true, Format::Dxt1, false) => (TextureFormat::Bc1RgbaUnorm, dds.data),
+            (true, Format::Dxt3, true) => (TextureFormat::Bc2RgbaUnormSrgb, dds.data),
+            (true, Format::Dxt3, false) => (TextureFormat::Bc2RgbaUnorm, dds.data),
+            (true, _, true) => (TextureFormat::Bc3RgbaUnormSrgb, dds.data),
+            (true, _, false) => (TextureFormat::Bc3RgbaUnorm, dds.data),
+            (false, _, true) => (TextureFormat::Rgba8UnormSrgb, dds.decode_rgba8()),
+            (false, _, false) => (TextureFormat::Rgba8Unorm, dds.decode_rgba8()),
+        };
+
+        let mut image = Image::default();
+        image.texture_descriptor.size = Extent3d { width: dds.width, height: dds.height, depth_or_array_layers: 1 };
+        image.texture_descriptor.dimension = TextureDimension::D2;
+        image.texture_descriptor.mip_level_count = dds.mip_levels;
+        image.texture_descriptor.format = format;
+        image.sampler = ImageSampler::Default;
+        image.asset_usage = RenderAssetUsages::default();
+        image.data = data;
+        image
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a DDS header for a four character code or an RGB bit count.
+    fn header(width: u32, height: u32, mips: u32, fourcc: Option<&[u8; 4]>, bits: u32) -> Vec<u8> {
+        let mut fields = [0u32; 31];
+        fields[0] = 124;
+        fields[1] = 0x1007 | if mips > 0 { DDSD_MIPMAPCOUNT } else { 0 };
+        fields[2] = height;
+        fields[3] = width;
+        fields[6] = mips;
+        fields[18] = 32;
+        if let Some(fourcc) = fourcc {
+            fields[19] = DDPF_FOURCC;
+            fields[20] = u32::from_le_bytes(*fourcc);
+        } else {
+            fields[19] = DDPF_RGB | DDPF_ALPHAPIXELS;
+            fields[21] = bits;
+            fields[22..26].copy_from_slice(&[0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0xFF00_0000]);
+        }
+        let mut bytes = b"DDS ".to_vec();
+        bytes.extend(fields.iter().flat_map(|field| field.to_le_bytes()));
+        bytes
+    }
+
+    /// BC1 block of red and blue, pixel indices 0, 1, 2, 3 repeating.
+    const BC1: [u8; 8] = [0x00, 0xF8, 0x1F, 0x00, 0xE4, 0xE4, 0xE4, 0xE4];
+
+    #[test]
+    fn bc1() {
+        let pixels = decode_bc1(&BC1, true);
+        assert_eq!(pixels[0], [255, 0, 0, 255]);
+        assert_eq!(pixels[1], [0, 0, 255, 255]);
+        assert_eq!(pixels[2], [170, 0, 85, 255]);
+        assert_eq!(pixels[3], [85, 0, 170, 255]);
+        assert_eq!(pixels[4], pixels[0]);
+    }
+
+    #[test]
+    fn bc1_punch_through() {
+        // c0 <= c1 selects three colours and transparent black
+        let block = [0x1F, 0x00, 0x00, 0xF8, 0xE4, 0xE4, 0xE4, 0xE4];
+        let pixels = decode_bc1(&block, true);
+        assert_eq!(pixels[2], [127, 0, 127, 255]);
+        assert_eq!(pixels[3], [0, 0, 0, 0]);
+        // colour blocks of DXT3 and DXT5 are always four colour
+        assert_eq!(decode_bc1(&block, false)[3][3], 255);
+    }
+
+    #[test]
+    fn bc2() {
+        let mut block = [0u8; 16];
+        block[0] = 0xF0; // pixel 0 alpha 0, pixel 1 alpha 15
+        block[8..].copy_from_slice(&BC1);
+        let pixels = decode_bc2(&block);
+        assert_eq!(pixels[0], [255, 0, 0, 0]);
+        assert_eq!(pixels[1], [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn bc3() {
+        let mut block = [0u8; 16];
+        block[0] = 255;
+        block[1] = 0;
+        block[2] = 0b1100_1000; // pixel 0 index 0, pixel 1 index 1, pixel 2 index 3 (low bits)
+        block[8..].copy_from_slice(&BC1);
+        let pixels = decode_bc3(&block);
+        assert_eq!(pixels[0][3], 255);
+        assert_eq!(pixels[1][3], 0);
+        assert_eq!(pixels[2][3], (255 * 5 / 7) as u8);
+
+        // a0 <= a1 has explicit 0 and 255 at indices 6 and 7
+        block[0] = 0;
+        block[1] = 100;
+        block[2] = 0b0011_1110; // pixel 0 index 6, pixel 1 index 7
+        let pixels = decode_bc3(&block);
+        assert_eq!(pixels[0][3], 0);
+        assert_eq!(pixels[1][3], 255);
+    }
+
+    #[test]
+    fn dxt1_mips() {
+        let mut bytes = header(8, 4, 4, Some(b"DXT1"), 0);
+        bytes.extend(BC1.repeat(2)); // 8x4
+        bytes.extend(BC1); // 4x2
+        bytes.extend(BC1); // 2x1
+        bytes.extend(BC1); // 1x1
+        let dds = Dds::parse(&bytes).unwrap();
+        assert_eq!(dds.format, Format::Dxt1);
+        assert_eq!((dds.width, dds.height, dds.mip_levels), (8, 4, 4));
+
+        let rgba = dds.decode_rgba8();
+        assert_eq!(rgba.len(), (32 + 8 + 2 + 1) * 4);
+        assert_eq!(rgba[..4], [255, 0, 0, 255]);
+        assert_eq!(rgba[4 * 4..4 * 5], [255, 0, 0, 255]); // second block
+        assert_eq!(rgba[32 * 4 + 4..32 * 4 + 8], [0, 0, 255, 255]); // second mip
+    }
+
+    #[test]
+    fn missing_mips() {
+        let mut bytes = header(4, 4, 3, Some(b"DXT5"), 0);
+        bytes.extend([0u8; 16]);
+        assert_eq!(Dds::parse(&bytes).unwrap().mip_levels, 1);
+        bytes.truncate(HEADER_SIZE + 4);
+        assert!(matches!(Dds::parse(&bytes), Err(Error::Truncated(_))));
+    }
+
+    #[test]
+    fn mip_count_clamped() {
+        // more levels than the chain has, with data to spare
+        let mut bytes = header(8, 4, 40, Some(b"DXT1"), 0);
+        bytes.extend(BC1.repeat(40));
+        assert_eq!(Dds::parse(&bytes).unwrap().mip_levels, 4);
+        let mut bytes = header(1, 1, u32::MAX, Some(b"DXT1"), 0);
+        bytes.extend(BC1.repeat(40));
+        assert_eq!(Dds::parse(&bytes).unwrap().mip_levels, 1);
+    }
+
+    #[test]
+    fn zero_size() {
+        let mut bytes = header(0, 4, 1, Some(b"DXT1"), 0);
+        bytes.extend(BC1);
+        assert!(matches!(Dds::parse(&bytes), Err(Error::Size(0, 4))));
+        let mut bytes = header(4, 0, 1, Some(b"DXT1"), 0);
+        bytes.extend(BC1);
+        assert!(matches!(Dds::parse(&bytes), Err(Error::Size(4, 0))));
+    }
+
+    #[test]
+    fn uncompressed() {
+        let mut bytes = header(2, 1, 0, None, 32);
+        bytes.extend([0x30, 0x20, 0x10, 0x80, 0x01, 0x02, 0x03, 0x04]); // BGRA
+        let dds = Dds::parse(&bytes).unwrap();
+        assert!(!dds.is_compressed());
+        assert_eq!(dds.decode_rgba8(), [0x10, 0x20, 0x30, 0x80, 0x03, 0x02, 0x01, 0x04]);
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(matches!(Dds::parse(&header(4, 4, 1, Some(b"ATI2"), 0)), Err(Error::Format(_))));
+        assert!(matches!(Dds::parse(b"PNG "), Err(Error::Truncated(_))));
+        let mut bytes = header(4, 4, 1, Some(b"DXT1"), 0);
+        bytes[0] = b'X';
+        assert!(matches!(Dds::parse(&bytes), Err(Error::Magic(_))));
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut bytes = header(4, 4, 1, Some(b"DXT1"), 0);
+        bytes.extend(BC1);
+        let dds = Dds::parse(&bytes).unwrap();
+
+        let image = DdsLoader { supported: CompressedImageFormats::BC }.image(dds.clone(), true);
+        assert_eq!(image.texture_descriptor.format, TextureFormat::Bc1RgbaUnormSrgb);
+        assert_eq!(image.data, BC1);
+
+        let image = DdsLoader { supported: CompressedImageFormats::NONE }.image(dds, false);
+        assert_eq!(image.texture_descriptor.format, TextureFormat::Rgba8Unorm);
+        assert_eq!(image.data.len(), 4 * 4 * 4);
+    }
+}
@@ -0,0 +1,683 @@
+//! Elder Scrolls master (ESM) and plugin (ESP) file parsing.
+//!
+//! A plugin is a TES4 header record followed by top level groups. Groups
+//! (GRUP) nest further groups and records, records hold subrecords.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    num::ParseIntError,
+    slice,
+    str::FromStr,
+};
+
+use flate2::read::ZlibDecoder;
+
+/// Size of a record or group header in Fallout 3 and New Vegas plugins.
+const HEADER_SIZE: usize = 24;
+
+/// Size of a subrecord header.
+const FIELD_HEADER_SIZE: usize = 6;
+
+/// Deepest group nesting accepted, vanilla plugins use five levels.
+const MAX_DEPTH: usize = 16;
+
+/// Deflate expands at most about 1032:1, caps preallocation for sizes read from the plugin.
+const MAX_RATIO: usize = 1032;
+
+/// Master file flag of the TES4 header record.
+pub const FLAG_MASTER: u32 = 0x0000_0001;
+/// Record is deleted.
+pub const FLAG_DELETED: u32 = 0x0000_0020;
+/// Reference is initially disabled.
+pub const FLAG_DISABLED: u32 = 0x0000_0800;
+/// Record data is zlib compressed, prefixed with the decompressed size.
+pub const FLAG_COMPRESSED: u32 = 0x0004_0000;
+
+/// Plugin parse error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error), // Underlying reader failed.
+    Magic([u8; 4]), // File doesn't start with a TES4 record.
+    Truncated(&'static str), // Data ended inside the named structure.
+    GroupSize(u32), // Group size smaller than its own header.
+    Depth, // Groups nested deeper than `MAX_DEPTH`.
+    Compression([u8; 4], FormId), // Compressed record failed to inflate.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Magic(magic) => write!(f, "not a plugin file (found {})", tag(magic)),
+            Error::Truncated(what) => write!(f, "truncated {what}"),
+            Error::GroupSize(size) => write!(f, "invalid group size {size}"),
+            Error::Depth => write!(f, "groups nested deeper than {MAX_DEPTH}"),
+            Error::Compression(kind, id) => write!(f, "failed to decompress {} {id}", tag(kind)),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated("file"),
+            _ => Error::Io(error),
+        }
+    }
+}
+
+/// Render a four character code for display.
+pub fn tag(kind: &[u8; 4]) -> String {
+    kind.iter().map(|&b| b as char).collect()
+}
+
+//------------------------------------------------------------------------------
+
+/// Form identifier, the high byte indexes the plugin's master list.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FormId(pub u32);
+
+impl FormId {
+    /// Index into the owning plugin's masters, the plugin itself when equal to the master count.
+    pub fn master(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    /// Identifier within the owning plugin.
+    pub fn object(self) -> u32 {
+        self.0 & 0x00FF_FFFF
+    }
+}
+
+impl fmt::Display for FormId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X}", self.0)
+    }
+}
+
+impl FromStr for FormId {
+    type Err = ParseIntError;
+
+    /// Parse hexadecimal, with or without a 0x prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+        u32::from_str_radix(s, 16).map(FormId)
+    }
+}
+
+/// Record header.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RecordHeader {
+    pub kind: [u8; 4], // Record type, e.g. REFR.
+    pub size: u32, // Data size as stored, excluding this header.
+    pub flags: u32, // See the FLAG_ constants.
+    pub id: FormId,
+    #[allow(dead_code)] // version control info, only shown by `Debug`
+    pub revision: u32,
+    #[allow(dead_code)] // form version, 15 for FO3 and NV, both are read the same
+    pub version: u16,
+    #[allow(dead_code)] // no known meaning
+    pub unknown: u16,
+}
+
+/// Group type and label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKind {
+    Top([u8; 4]), // Every record of a type, labelled by that type.
+    WorldChildren(FormId),
+    InteriorBlock(i32),
+    InteriorSubBlock(i32),
+    ExteriorBlock { x: i16, y: i16 },
+    ExteriorSubBlock { x: i16, y: i16 },
+    CellChildren(FormId),
+    TopicChildren(FormId),
+    CellPersistent(FormId),
+    CellTemporary(FormId),
+    CellVisibleDistant(FormId),
+    Unknown(i32, [u8; 4]),
+}
+
+impl GroupKind {
+    fn new(kind: i32, label: [u8; 4]) -> Self {
+        let id = FormId(u32::from_le_bytes(label));
+        let number = i32::from_le_bytes(label);
+        let y = i16::from_le_bytes([label[0], label[1]]);
+        let x = i16::from_le_bytes([label[2], label[3]]);
+        match kind {
+            0 => GroupKind::Top(label),
+            1 => GroupKind::WorldChildren(id),
+            2 => GroupKind::InteriorBlock(number),
+            3 => GroupKind::InteriorSubBlock(number),
+            4 => GroupKind::ExteriorBlock { x, y },
+            5 => GroupKind::ExteriorSubBlock { x, y },
+            6 => GroupKind::CellChildren(id),
+            7 => GroupKind::TopicChildren(id),
+            8 => GroupKind::CellPersistent(id),
+            9 => GroupKind::CellTemporary(id),
+            10 => GroupKind::CellVisibleDistant(id),
+            _ => GroupKind::Unknown(kind, label),
+        }
+    }
+}
+
+/// Group header.
+#[derive(Debug, Clone, Copy)]
+pub struct GroupHeader {
+    pub size: u32, // Size including this header.
+    pub kind: GroupKind,
+    #[allow(dead_code)] // modification date, only shown by `Debug`
+    pub stamp: u16,
+    #[allow(dead_code)] // no known meaning
+    pub unknown: u16,
+    #[allow(dead_code)] // form version of the records inside, only shown by `Debug`
+    pub version: u16,
+    #[allow(dead_code)] // no known meaning
+    pub unknown2: u16,
+}
+
+/// A record with its decompressed data.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub header: RecordHeader,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Iterate subrecords.
+    pub fn subrecords(&self) -> Subrecords<'_> {
+        Subrecords { rest: &self.data, oversize: None }
+    }
+
+    /// First subrecord of the given type.
+    pub fn get(&self, kind: &[u8; 4]) -> Option<Subrecord<'_>> {
+        self.subrecords().map_while(Result::ok).find(|field| field.kind == *kind)
+    }
+
+    /// The EDID subrecord, if present.
+    pub fn editor_id(&self) -> Option<String> {
+        self.get(b"EDID").map(|field| field.zstring())
+    }
+}
+
+/// A subrecord borrowed from its record.
+#[derive(Debug, Clone, Copy)]
+pub struct Subrecord<'a> {
+    pub kind: [u8; 4],
+    pub data: &'a [u8],
+}
+
+impl Subrecord<'_> {
+    /// Data as a null terminated string.
+    pub fn zstring(&self) -> String {
+        zstring(self.data)
+    }
+
+    /// Little endian u32 at offset.
+    pub fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32_at(bytes, 0))
+    }
+
+    /// Little endian f32 at offset.
+    pub fn f32(&self, offset: usize) -> Option<f32> {
+        self.u32(offset).map(f32::from_bits)
+    }
+}
+
+/// Subrecord iterator, resolves XXXX oversized fields.
+pub struct Subrecords<'a> {
+    rest: &'a [u8],
+    oversize: Option<usize>, // XXXX overrides the next subrecord size
+}
+
+impl<'a> Iterator for Subrecords<'a> {
+    type Item = Result<Subrecord<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            if self.rest.len() < FIELD_HEADER_SIZE {
+                self.rest = &[];
+                return Some(Err(Error::Truncated("subrecord header")));
+            }
+            let kind = [self.rest[0], self.rest[1], self.rest[2], self.rest[3]];
+            let size = self.oversize.take().unwrap_or(u16_at(self.rest, 4) as usize);
+            let Some(data) = self.rest.get(FIELD_HEADER_SIZE..FIELD_HEADER_SIZE + size) else {
+                self.rest = &[];
+                return Some(Err(Error::Truncated("subrecord")));
+            };
+            self.rest = &self.rest[FIELD_HEADER_SIZE + size..];
+
+            if kind == *b"XXXX" && size == 4 {
+                self.oversize = Some(u32_at(data, 0) as usize);
+                continue;
+            }
+            return Some(Ok(Subrecord { kind, data }));
+        }
+    }
+}
+
+/// A group and its children.
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub header: GroupHeader,
+    pub entries: Vec<Entry>,
+}
+
+impl Group {
+    /// Groups directly inside this group.
+    pub fn groups(&self) -> impl Iterator<Item = &Group> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Group(group) => Some(group),
+            Entry::Record(_) => None,
+        })
+    }
+
+    /// Depth first iteration over every record in this group and its children.
+    pub fn descendants(&self) -> Records<'_> {
+        Records { groups: slice::from_ref(self).iter(), stack: Vec::new() }
+    }
+
+    /// This group or the first descendant group of the given kind.
+    pub fn find_group(&self, kind: GroupKind) -> Option<&Group> {
+        if self.header.kind == kind {
+            return Some(self);
+        }
+        self.groups().find_map(|group| group.find_group(kind))
+    }
+}
+
+/// Group child.
+#[derive(Debug, Clone)]
+pub enum Entry {
+    Record(Record),
+    Group(Group),
+}
+
+/// Plugin header, parsed from the leading TES4 record.
+#[derive(Debug, Default, Clone)]
+pub struct Header {
+    pub flags: u32, // Record flags, see `FLAG_MASTER`.
+    pub version: f32, // HEDR version, 0.94 for FO3 and 1.34 for FNV.
+    pub records: i32, // HEDR number of records and groups.
+    pub next_id: u32, // HEDR next available object id.
+    pub author: String, // CNAM
+    pub description: String, // SNAM
+    pub masters: Vec<String>, // MAST
+}
+
+impl Header {
+    /// Whether the master flag is set.
+    pub fn is_master(&self) -> bool {
+        self.flags & FLAG_MASTER != 0
+    }
+
+    /// Interpret a TES4 record.
+    pub fn from_record(record: &Record) -> Result<Self, Error> {
+        if record.header.kind != *b"TES4" {
+            return Err(Error::Magic(record.header.kind));
+        }
+        let mut header = Header { flags: record.header.flags, ..Default::default() };
+        for field in record.subrecords() {
+            let field = field?;
+            match &field.kind {
+                b"HEDR" if field.data.len() >= 12 => {
+                    header.version = f32::from_bits(u32_at(field.data, 0));
+                    header.records = u32_at(field.data, 4) as i32;
+                    header.next_id = u32_at(field.data, 8);
+                },
+                b"CNAM" => header.author = field.zstring(),
+                b"SNAM" => header.description = field.zstring(),
+                b"MAST" => header.masters.push(field.zstring()),
+                _ => {} // OFST, DELE, DATA, ONAM, etc.
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// A fully parsed plugin.
+#[derive(Debug, Clone)]
+pub struct Plugin {
+    pub header: Header,
+    pub groups: Vec<Group>,
+}
+
+impl Plugin {
+    /// Parse a whole plugin held in memory.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut rest = bytes;
+        let header = match read_entry(&mut rest, 0)? {
+            Entry::Record(record) => Header::from_record(&record)?,
+            Entry::Group(_) => return Err(Error::Magic(*b"GRUP")),
+        };
+
+        let mut groups = Vec::new();
+        while !rest.is_empty() {
+            match read_entry(&mut rest, 0)? {
+                Entry::Group(group) => groups.push(group),
+                Entry::Record(record) => return Err(Error::Magic(record.header.kind)),
+            }
+        }
+
+        Ok(Plugin { header, groups })
+    }
+
+    /// Read and parse a whole plugin.
+    pub fn read(mut reader: impl Read) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::parse(&bytes)
+    }
+
+    /// Depth first iteration over every record in the plugin.
+    pub fn records(&self) -> Records<'_> {
+        Records { groups: self.groups.iter(), stack: Vec::new() }
+    }
+
+    /// Find a record by form id, as stored in this plugin.
+    pub fn find(&self, id: FormId) -> Option<&Record> {
+        self.records().find(|record| record.header.id == id)
+    }
+
+    /// Find a group of the given kind anywhere in the plugin.
+    pub fn find_group(&self, kind: GroupKind) -> Option<&Group> {
+        self.groups.iter().find_map(|group| group.find_group(kind))
+    }
+}
+
+/// Depth first record iterator.
+pub struct Records<'a> {
+    groups: slice::Iter<'a, Group>, // remaining top level groups
+    stack: Vec<slice::Iter<'a, Entry>>, // entries of each open group
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = &'a Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(entries) = self.stack.last_mut() else {
+                self.stack.push(self.groups.next()?.entries.iter());
+                continue;
+            };
+            match entries.next() {
+                Some(Entry::Record(record)) => return Some(record),
+                Some(Entry::Group(group)) => self.stack.push(group.entries.iter()),
+                None => { self.stack.pop(); },
+            }
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Read a record or group from the front of a buffer, advancing it.
+fn read_entry(rest: &mut &[u8], depth: usize) -> Result<Entry, Error> {
+    let Some(head) = rest.get(..HEADER_SIZE) else {
+        return Err(Error::Truncated("record header"));
+    };
+
+    if head[..4] == *b"GRUP" {
+        let header = GroupHeader {
+            size: u32_at(head, 4),
+            kind: GroupKind::new(u32_at(head, 12) as i32, [head[8], head[9], head[10], head[11]]),
+            stamp: u16_at(head, 16),
+            unknown: u16_at(head, 18),
+            version: u16_at(head, 20),
+            unknown2: u16_at(head, 22),
+        };
+        let size = header.size as usize;
+        if size < HEADER_SIZE {
+            return Err(Error::GroupSize(header.size));
+        }
+        let Some(mut body) = rest.get(HEADER_SIZE..size) else {
+            return Err(Error::Truncated("group"));
+        };
+        if depth >= MAX_DEPTH {
+            return Err(Error::Depth);
+        }
+        *rest = &rest[size..];
+
+        let mut entries = Vec::new();
+        while !body.is_empty() {
+            entries.push(read_entry(&mut body, depth + 1)?);
+        }
+        return Ok(Entry::Group(Group { header, entries }));
+    }
+
+    let header = record_header(head);
+    let size = HEADER_SIZE + header.size as usize;
+    let Some(data) = rest.get(HEADER_SIZE..size) else {
+        return Err(Error::Truncated("record"));
+    };
+    *rest = &rest[size..];
+
+    let data = if header.flags & FLAG_COMPRESSED != 0 {
+        inflate(data).ok_or(Error::Compression(header.kind, header.id))?
+    } else {
+        data.to_vec()
+    };
+    Ok(Entry::Record(Record { header, data }))
+}
+
+/// Parse a record header, caller guarantees length.
+fn record_header(head: &[u8]) -> RecordHeader {
+    RecordHeader {
+        kind: [head[0], head[1], head[2], head[3]],
+        size: u32_at(head, 4),
+        flags: u32_at(head, 8),
+        id: FormId(u32_at(head, 12)),
+        revision: u32_at(head, 16),
+        version: u16_at(head, 20),
+        unknown: u16_at(head, 22),
+    }
+}
+
+/// Decompress record data prefixed by its decompressed size.
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let size = u32_at(data.get(..4)?, 0) as usize;
+    let mut output = Vec::with_capacity(size.min((data.len() - 4).saturating_mul(MAX_RATIO)));
+    ZlibDecoder::new(&data[4..]).take(size as u64 + 1).read_to_end(&mut output).ok()?;
+    (output.len() == size).then_some(output)
+}
+
+/// Little endian u16 at offset, caller guarantees bounds.
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Little endian u32 at offset, caller guarantees bounds.
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Null terminated string, decoded as latin-1.
+fn zstring(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end].iter().map(|&b| b as char).collect()
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    /// Build a subrecord.
+    fn field(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = kind.to_vec();
+        bytes.extend((data.len() as u16).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    /// Build a record from its subrecords.
+    fn record(kind: &[u8; 4], flags: u32, id: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = kind.to_vec();
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(flags.to_le_bytes());
+        bytes.extend(id.to_le_bytes());
+        bytes.extend([0; 4]); // revision
+        bytes.extend(15u16.to_le_bytes());
+        bytes.extend([0; 2]);
+        bytes.extend(data);
+        bytes
+    }
+
+    /// Build a group from its children.
+    fn group(kind: i32, label: [u8; 4], children: &[u8]) -> Vec<u8> {
+        let mut bytes = b"GRUP".to_vec();
+        bytes.extend((HEADER_SIZE as u32 + children.len() as u32).to_le_bytes());
+        bytes.extend(label);
+        bytes.extend(kind.to_le_bytes());
+        bytes.extend([0; 8]);
+        bytes.extend(children);
+        bytes
+    }
+
+    /// A TES4 header record with a single master.
+    fn tes4() -> Vec<u8> {
+        let mut hedr = 0.94f32.to_le_bytes().to_vec();
+        hedr.extend(3i32.to_le_bytes());
+        hedr.extend(0x800u32.to_le_bytes());
+        let data = [
+            field(b"HEDR", &hedr),
+            field(b"CNAM", b"author\0"),
+            field(b"SNAM", b"description\0"),
+            field(b"MAST", b"Fallout3.esm\0"),
+            field(b"DATA", &[0; 8]),
+        ].concat();
+        record(b"TES4", FLAG_MASTER, 0, &data)
+    }
+
+    #[test]
+    fn header() {
+        let header = Plugin::parse(&tes4()).unwrap().header;
+        assert!(header.is_master());
+        assert_eq!(header.version, 0.94);
+        assert_eq!(header.records, 3);
+        assert_eq!(header.next_id, 0x800);
+        assert_eq!(header.author, "author");
+        assert_eq!(header.description, "description");
+        assert_eq!(header.masters, ["Fallout3.esm"]);
+    }
+
+    #[test]
+    fn not_a_plugin() {
+        let bytes = record(b"STAT", 0, 1, &[]);
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Magic(kind)) if kind == *b"STAT"));
+        assert!(matches!(Plugin::parse(b"TES4"), Err(Error::Truncated(_))));
+        let mut bytes = tes4();
+        bytes[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Truncated("record"))));
+    }
+
+    #[test]
+    fn groups() {
+        let stat = record(b"STAT", 0, 0x0100_0801, &field(b"EDID", b"Rock\0"));
+        let refr = record(b"REFR", FLAG_DISABLED, 0x0100_0803, &field(b"NAME", &0x0100_0801u32.to_le_bytes()));
+        let cell = record(b"CELL", 0, 0x0100_0802, &field(b"EDID", b"Vault\0"));
+        let children = group(6, 0x0100_0802u32.to_le_bytes(), &group(8, 0x0100_0802u32.to_le_bytes(), &refr));
+        let cells = group(0, *b"CELL", &group(2, 1i32.to_le_bytes(), &group(3, 2i32.to_le_bytes(), &[cell, children].concat())));
+        let bytes = [tes4(), group(0, *b"STAT", &stat), cells].concat();
+
+        let plugin = Plugin::parse(&bytes).unwrap();
+        assert_eq!(plugin.groups.len(), 2);
+        assert_eq!(plugin.find_group(GroupKind::Top(*b"STAT")).unwrap().descendants().count(), 1);
+
+        let kinds: Vec<_> = plugin.records().map(|record| tag(&record.header.kind)).collect();
+        assert_eq!(kinds, ["STAT", "CELL", "REFR"]);
+
+        let block = plugin.find_group(GroupKind::Top(*b"CELL")).unwrap().groups().next().unwrap();
+        assert_eq!(block.header.kind, GroupKind::InteriorBlock(1));
+        let sub_block = block.groups().next().unwrap();
+        assert_eq!(sub_block.header.kind, GroupKind::InteriorSubBlock(2));
+        let children = sub_block.groups().next().unwrap();
+        assert_eq!(children.header.kind, GroupKind::CellChildren(FormId(0x0100_0802)));
+
+        let refr = plugin.find(FormId(0x0100_0803)).unwrap();
+        assert_eq!(refr.header.flags, FLAG_DISABLED);
+        assert_eq!(refr.header.id.master(), 1);
+        assert_eq!(refr.header.id.object(), 0x803);
+        assert_eq!(refr.get(b"NAME").unwrap().u32(0), Some(0x0100_0801));
+        assert_eq!(plugin.find(FormId(0x0100_0801)).unwrap().editor_id().unwrap(), "Rock");
+
+        let persistent = plugin.find_group(GroupKind::CellPersistent(FormId(0x0100_0802))).unwrap();
+        assert_eq!(persistent.descendants().count(), 1);
+        assert_eq!(plugin.find_group(GroupKind::Top(*b"CELL")).unwrap().descendants().count(), 2);
+        assert!(plugin.find_group(GroupKind::CellTemporary(FormId(0x0100_0802))).is_none());
+    }
+
+    #[test]
+    fn exterior_block() {
+        let label = [(-2i16).to_le_bytes(), 5i16.to_le_bytes()].concat();
+        let bytes = [tes4(), group(0, *b"WRLD", &group(4, label.try_into().unwrap(), &[]))].concat();
+        let plugin = Plugin::parse(&bytes).unwrap();
+        let block = plugin.groups[0].groups().next().unwrap();
+        assert_eq!(block.header.kind, GroupKind::ExteriorBlock { x: 5, y: -2 });
+    }
+
+    #[test]
+    fn oversized_subrecord() {
+        let big = vec![7u8; 70_000];
+        let mut data = field(b"XXXX", &(big.len() as u32).to_le_bytes());
+        data.extend(b"OFST");
+        data.extend(0u16.to_le_bytes());
+        data.extend(&big);
+        data.extend(field(b"EDID", b"After\0"));
+        let record = Record { header: RecordHeader::default(), data };
+
+        let fields: Vec<_> = record.subrecords().collect::<Result<_, _>>().unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(&fields[0].kind, b"OFST");
+        assert_eq!(fields[0].data.len(), 70_000);
+        assert_eq!(record.editor_id().unwrap(), "After");
+    }
+
+    #[test]
+    fn compressed_record() {
+        let data = field(b"EDID", b"Squashed\0");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let mut packed = (data.len() as u32).to_le_bytes().to_vec();
+        packed.extend(encoder.finish().unwrap());
+
+        let bytes = [tes4(), group(0, *b"NPC_", &record(b"NPC_", FLAG_COMPRESSED, 0x10, &packed))].concat();
+        let plugin = Plugin::parse(&bytes).unwrap();
+        assert_eq!(plugin.find(FormId(0x10)).unwrap().editor_id().unwrap(), "Squashed");
+
+        let bytes = [tes4(), group(0, *b"NPC_", &record(b"NPC_", FLAG_COMPRESSED, 0x10, &[4, 0, 0, 0, 1]))].concat();
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Compression(..))));
+
+        // sizes read from the plugin are not trusted for allocation
+        packed[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let bytes = [tes4(), group(0, *b"NPC_", &record(b"NPC_", FLAG_COMPRESSED, 0x10, &packed))].concat();
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Compression(..))));
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |depth| (0..depth).fold(Vec::new(), |children, _| group(1, *b"WRLD", &children));
+        let bytes = [tes4(), group(0, *b"WRLD", &nested(MAX_DEPTH - 1))].concat();
+        assert_eq!(Plugin::parse(&bytes).unwrap().groups[0].descendants().count(), 0);
+
+        let bytes = [tes4(), group(0, *b"WRLD", &nested(MAX_DEPTH))].concat();
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Depth)));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut bytes = [tes4(), group(0, *b"STAT", &record(b"STAT", 0, 1, &field(b"EDID", b"Rock\0")))].concat();
+        bytes.truncate(bytes.len() - 2);
+        assert!(matches!(Plugin::parse(&bytes), Err(Error::Truncated("group"))));
+
+        let record = Record { header: RecordHeader::default(), data: field(b"EDID", b"Rock")[..7].to_vec() };
+        assert!(matches!(record.subrecords().next(), Some(Err(Error::Truncated("subrecord")))));
+    }
+}
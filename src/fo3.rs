@@ -1,14 +1,61 @@
 //! Placeholder for Fallout 3.
 
+use std::path::PathBuf;
+
 use bevy::prelude::*;
 
-pub struct Fallout3Plugin;
+use crate::cell::{command_coc, complete_coc, spawn_cell, SpawnCell, CELL_SIZE};
+use crate::console::{ConsoleCommandExt, StdErrEvent, StdOutEvent};
+use crate::data::{Archives, DataDirectory};
+use crate::dds::DdsLoader;
+use crate::load_order::{command_conflicts, command_load, command_loadorder, complete_load, LoadOrder};
+use crate::nif::NifLoader;
+
+/// Archives loaded by the original game, lowest priority first.
+const ARCHIVES: &[&str] = &[
+    "Fallout - Textures.bsa",
+    "Fallout - Meshes.bsa",
+    "Fallout - Voices.bsa",
+    "Fallout - Sound.bsa",
+    "Fallout - MenuVoices.bsa",
+    "Fallout - Misc.bsa",
+];
+
+pub struct Fallout3Plugin {
+    pub data: PathBuf, // Game data directory.
+    pub archives: Vec<String>, // Archives to mount from the data directory, lowest priority first.
+}
+
+impl Default for Fallout3Plugin {
+    fn default() -> Self {
+        Self {
+            data: "Data".into(),
+            archives: ARCHIVES.iter().map(|archive| archive.to_string()).collect(),
+        }
+    }
+}
 
 impl Plugin for Fallout3Plugin {
     fn build(&self, app: &mut App) {
-        println!("Fallout3Plugin::build()");
-        app.add_systems(Startup, setup);
-        app.add_systems(Update, rotate);
+        info!("Fallout3Plugin::build()");
+        app.init_asset_loader::<NifLoader>();
+        app.insert_resource(DataDirectory(self.data.clone()));
+        app.insert_resource(ArchiveList(self.archives.iter().map(|archive| self.data.join(archive)).collect()));
+        app.init_resource::<LoadOrder>();
+        app.add_event::<SpawnCell>();
+        app.add_systems(Startup, (setup, mount_archives));
+        app.add_systems(Update, spawn_cell);
+        app.register_console_command("coc", "Spawn a cell: coc EditorID or coc world x y.", command_coc);
+        app.register_console_completer("coc", complete_coc);
+        app.register_console_command("conflicts", "List plugins overriding a record.", command_conflicts);
+        app.register_console_command("load", "Load an ESM or ESP file from the data directory.", command_load);
+        app.register_console_completer("load", complete_load);
+        app.register_console_command("loadorder", "List loaded plugins.", command_loadorder);
+    }
+
+    fn finish(&self, app: &mut App) {
+        // needs the render device to know which compressed formats are supported
+        app.init_asset_loader::<DdsLoader>();
     }
 }
 
@@ -17,23 +64,42 @@ impl Plugin for Fallout3Plugin {
 #[derive(Component)]
 pub struct CameraUi;
 
-/// Set up a simple 3D scene
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+/// Archives to mount at startup.
+#[derive(Resource)]
+struct ArchiveList(Vec<PathBuf>);
+
+/// Mount configured archives into the data asset source.
+fn mount_archives(
+    list: Res<ArchiveList>,
+    archives: Res<Archives>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
 ) {
-    println!("Fallout3Plugin::setup()");
+    for path in &list.0 {
+        match archives.mount(path) {
+            Ok(()) => { stdout.send(StdOutEvent::new(format!("mounted {}\n", path.display()))); },
+            Err(error) => { stderr.send(StdErrEvent::new(format!("mount: {}: {error}\n", path.display()))); },
+        }
+    }
+}
+
+/// Set up cameras and lighting, cells are spawned by `coc`.
+fn setup(mut commands: Commands) {
+    info!("Fallout3Plugin::setup()");
     // 3D camera is used to render 3D scenes.
     commands.spawn((
         Camera3dBundle {
-            transform: Transform::from_xyz(0.0, 0.0, 5.0)
+            transform: Transform::from_xyz(0.0, 512.0, 1024.0)
                 .looking_at(Vec3::default(), Vec3::Y),
             camera: Camera {
                 order: 0,
                 clear_color: Color::srgb(0.65, 0.65, 0.65).into(),
                 ..default()
             },
+            projection: PerspectiveProjection {
+                far: 32.0 * CELL_SIZE, // game units
+                ..default()
+            }.into(),
             ..default()
         },
     ));
@@ -55,17 +121,6 @@ fn setup(
 //        },
     ));
 
-    // cube
-    commands.spawn((
-        PbrBundle {
-            mesh: meshes.add(Cuboid::default()),
-            material: materials.add(Color::srgb(0.8, 0.7, 0.6)),
-            transform: Transform::from_xyz(0.0, 0.5, 0.0),
-            ..default()
-        },
-        Rotates,
-    ));
-
     // light
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -75,14 +130,3 @@ fn setup(
         ..default()
     });
 }
-
-#[derive(Component)]
-struct Rotates;
-
-/// Rotates any entity around the x and y axis
-fn rotate(time: Res<Time>, mut query: Query<&mut Transform, With<Rotates>>) {
-    for mut transform in &mut query {
-        transform.rotate_x(0.55 * time.delta_seconds());
-        transform.rotate_z(0.15 * time.delta_seconds());
-    }
-}
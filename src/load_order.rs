@@ -0,0 +1,367 @@
+//! Plugin load order and form id resolution.
+//!
+//! The high byte of a form id indexes the master list of the plugin storing
+//! it, or the plugin itself when past the end of that list. Loaded plugins are
+//! assigned load order indices and their form ids remapped to global ids with
+//! the load order index as the high byte. The last plugin defining a record
+//! wins. EditorIDs are indexed as plugins are added.
+
+use std::{fmt, fs::File, io::BufReader, path::Path};
+
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+
+use crate::console::{complete_path, ConsoleArgs, StdErrEvent, StdOutEvent};
+use crate::data::DataDirectory;
+use crate::esm::{self, FormId, Plugin, Record};
+
+/// Maximum number of plugins, index 0xFF is reserved for runtime forms.
+pub const MAX_PLUGINS: usize = 0xFF;
+
+/// Load order error.
+#[derive(Debug)]
+pub enum Error {
+    Loaded(String), // Plugin is already in the load order.
+    Master(String, String), // Plugin and the master it's missing.
+    Full, // No indices left.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Loaded(name) => write!(f, "{name}: already loaded"),
+            Error::Master(name, master) => write!(f, "{name}: missing master {master}"),
+            Error::Full => write!(f, "load order is full"),
+        }
+    }
+}
+
+/// A plugin in the load order.
+#[derive(Debug)]
+pub struct LoadedPlugin {
+    pub name: String, // File name, masters refer to plugins by it.
+    pub plugin: Plugin,
+    masters: Vec<u8>, // Load order index of each master.
+}
+
+/// A record found by EditorID.
+#[derive(Debug, Clone)]
+pub struct EditorId {
+    pub name: String, // EditorID as written by the plugin defining it.
+    pub kind: [u8; 4], // Record type.
+    pub id: FormId, // Global form id.
+}
+
+/// Loaded plugins, lowest priority first.
+#[derive(Resource, Default)]
+pub struct LoadOrder {
+    plugins: Vec<LoadedPlugin>,
+    editor_ids: HashMap<String, EditorId>, // By lowercase EditorID, the last plugin defining one wins, overridden records drop theirs.
+}
+
+impl LoadOrder {
+    /// Append a plugin, its masters must already be loaded.
+    pub fn push(&mut self, path: impl AsRef<Path>, plugin: Plugin) -> Result<usize, Error> {
+        let name = path.as_ref().file_name().unwrap_or_default().to_string_lossy().into_owned();
+        if self.index(&name).is_some() {
+            return Err(Error::Loaded(name));
+        }
+        if self.plugins.len() >= MAX_PLUGINS {
+            return Err(Error::Full);
+        }
+        let masters = plugin.header.masters.iter()
+            .map(|master| self.index(master).map(|index| index as u8).ok_or_else(|| Error::Master(name.clone(), master.clone())))
+            .collect::<Result<_, _>>()?;
+        self.plugins.push(LoadedPlugin { name, plugin, masters });
+
+        let index = self.plugins.len() - 1;
+        let mut ids = HashSet::new();
+        let mut editor_ids = Vec::new();
+        for record in self.plugins[index].plugin.records() {
+            let id = self.global(index, record.header.id);
+            ids.insert(id);
+            if let Some(name) = record.editor_id() {
+                editor_ids.push((name.to_lowercase(), EditorId { name, kind: record.header.kind, id }));
+            }
+        }
+        // overrides replace the EditorID of the record they override
+        self.editor_ids.retain(|_, editor_id| !ids.contains(&editor_id.id));
+        self.editor_ids.extend(editor_ids);
+        Ok(index)
+    }
+
+    /// Record with an EditorID, case insensitive.
+    pub fn editor_id(&self, editor_id: &str) -> Option<&EditorId> {
+        self.editor_ids.get(&editor_id.to_lowercase())
+    }
+
+    /// Every indexed EditorID, in no particular order.
+    pub fn editor_ids(&self) -> impl Iterator<Item = &EditorId> {
+        self.editor_ids.values()
+    }
+
+    /// Load order index of a plugin by file name.
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.plugins.iter().position(|loaded| loaded.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Plugins with their load order indices.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (usize, &LoadedPlugin)> {
+        self.plugins.iter().enumerate()
+    }
+
+    /// Whether no plugins are loaded.
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Remap a form id stored in the plugin at `index` to a global id.
+    pub fn global(&self, index: usize, id: FormId) -> FormId {
+        let masters = &self.plugins[index].masters;
+        let owner = masters.get(id.master() as usize).copied().unwrap_or(index as u8);
+        FormId((owner as u32) << 24 | id.object())
+    }
+
+    /// Remap a global form id to the id stored in the plugin at `index`, if it can refer to it.
+    pub fn local(&self, index: usize, id: FormId) -> Option<FormId> {
+        let masters = &self.plugins[index].masters;
+        let owner = id.master();
+        let master = if owner as usize == index {
+            masters.len()
+        } else {
+            masters.iter().position(|&master| master == owner)?
+        };
+        Some(FormId((master as u32) << 24 | id.object()))
+    }
+
+    /// Every version of a record, lowest priority first so the last one wins.
+    pub fn overrides(&self, id: FormId) -> Vec<(usize, &Record)> {
+        self.iter()
+            .filter_map(|(index, loaded)| Some((index, loaded.plugin.find(self.local(index, id)?)?)))
+            .collect()
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Print the load order.
+pub fn command_loadorder(
+    In(args): In<ConsoleArgs>,
+    order: Res<LoadOrder>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=0, "") {
+        stderr.send(error);
+        return;
+    }
+    if order.is_empty() {
+        stderr.send(StdErrEvent::new("loadorder: no plugins loaded\n"));
+        return;
+    }
+    let mut value = String::new();
+    for (index, loaded) in order.iter() {
+        value.push_str(&format!("{index:02X} {}\n", loaded.name));
+    }
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Print every plugin defining a record, the last one wins.
+pub fn command_conflicts(
+    In(args): In<ConsoleArgs>,
+    order: Res<LoadOrder>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(1..=1, "FormID") {
+        stderr.send(error);
+        return;
+    }
+    let Ok(id) = args[0].parse::<FormId>() else {
+        stderr.send(StdErrEvent::new(format!("conflicts: {}: invalid form id\n", &args[0])));
+        return;
+    };
+
+    let overrides = order.overrides(id);
+    if overrides.is_empty() {
+        stderr.send(StdErrEvent::new(format!("conflicts: {id}: not found\n")));
+        return;
+    }
+    let mut value = String::new();
+    for (position, (index, record)) in overrides.iter().enumerate() {
+        let winner = if position + 1 == overrides.len() { " (wins)" } else { "" };
+        let editor_id = record.editor_id().unwrap_or_default();
+        let name = &order.plugins[*index].name;
+        value.push_str(&format!("{index:02X} {name} {} {editor_id}{winner}\n", esm::tag(&record.header.kind)));
+    }
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Load a plugin from the data directory, append it to the load order and print its header.
+pub fn command_load(
+    In(args): In<ConsoleArgs>,
+    data: Res<DataDirectory>,
+    mut order: ResMut<LoadOrder>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(1..=1, "filename") {
+        stderr.send(error);
+        return;
+    }
+    let path = &args[0];
+
+    let plugin = File::open(data.0.join(path))
+        .map_err(esm::Error::from)
+        .and_then(|file| esm::Plugin::read(BufReader::new(file)));
+    let plugin = match plugin {
+        Ok(plugin) => plugin,
+        Err(error) => {
+            stderr.send(StdErrEvent::new(format!("load: {path}: {error}\n")));
+            return;
+        }
+    };
+
+    let header = plugin.header.clone();
+    let index = match order.push(path, plugin) {
+        Ok(index) => index,
+        Err(error) => {
+            stderr.send(StdErrEvent::new(format!("load: {error}\n")));
+            return;
+        }
+    };
+
+    let esm::Header { version, records, ref author, ref description, ref masters, .. } = header;
+    let kind = if header.is_master() { "master" } else { "plugin" };
+    let mut value = format!(r#"File:        {path} ({kind})
+Index:       {index:02X}
+Version:     {version:.2}
+Records:     {records}
+Author:      {author}
+Description: {description}
+"#);
+    for (index, master) in masters.iter().enumerate() {
+        value.push_str(&format!("Master {index:02}:   {master}\n"));
+    }
+    stdout.send(StdOutEvent::new(value));
+}
+
+/// Complete plugin paths in the data directory for `load`.
+pub fn complete_load(In(args): In<ConsoleArgs>, data: Res<DataDirectory>) -> Vec<String> {
+    match args.args.len() {
+        1 => complete_path(&data.0, &args[0], &["esm", "esp"]),
+        _ => Vec::new(),
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esm::{Entry, Group, GroupHeader, GroupKind, Header, RecordHeader};
+
+    /// A plugin with the given masters and STAT records of (form id, editor id).
+    fn plugin(masters: &[&str], records: &[(u32, &str)]) -> Plugin {
+        let entries = records.iter().map(|&(id, editor_id)| {
+            let mut data = b"EDID".to_vec();
+            data.extend((editor_id.len() as u16 + 1).to_le_bytes());
+            data.extend(editor_id.as_bytes());
+            data.push(0);
+            let header = RecordHeader { kind: *b"STAT", size: data.len() as u32, id: FormId(id), ..Default::default() };
+            Entry::Record(Record { header, data })
+        }).collect();
+        let header = GroupHeader { size: 0, kind: GroupKind::Top(*b"STAT"), stamp: 0, unknown: 0, version: 0, unknown2: 0 };
+        Plugin {
+            header: Header { masters: masters.iter().map(|&master| master.into()).collect(), ..Default::default() },
+            groups: vec![Group { header, entries }],
+        }
+    }
+
+    /// Fallout3.esm, Mod.esp overriding it, and Patch.esp overriding only Mod.esp.
+    fn load_order() -> LoadOrder {
+        let mut order = LoadOrder::default();
+        order.push("Data/Fallout3.esm", plugin(&[], &[(0x0801, "Rock"), (0x0802, "Tree")])).unwrap();
+        order.push("Data/Mod.esp", plugin(&["Fallout3.esm"], &[(0x0801, "RockMod"), (0x0100_0900, "Lamp")])).unwrap();
+        order.push("Data/Patch.esp", plugin(&["Mod.esp"], &[(0x0900, "LampPatch"), (0x0100_0A00, "Sign")])).unwrap();
+        order
+    }
+
+    /// Plugin index and editor id of every version of a record.
+    fn overrides(order: &LoadOrder, id: u32) -> Vec<(usize, String)> {
+        order.overrides(FormId(id)).into_iter()
+            .map(|(index, record)| (index, record.editor_id().unwrap_or_default().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn push() {
+        let mut order = load_order();
+        assert_eq!(order.index("patch.ESP"), Some(2));
+        let names: Vec<_> = order.iter().map(|(_, loaded)| loaded.name.as_str()).collect();
+        assert_eq!(names, ["Fallout3.esm", "Mod.esp", "Patch.esp"]);
+
+        assert!(matches!(order.push("mod.esp", plugin(&[], &[])), Err(Error::Loaded(name)) if name == "mod.esp"));
+        let error = order.push("Other.esp", plugin(&["Fallout3.esm", "Anchorage.esm"], &[])).unwrap_err();
+        assert!(matches!(error, Error::Master(name, master) if name == "Other.esp" && master == "Anchorage.esm"));
+        assert_eq!(order.iter().count(), 3);
+    }
+
+    #[test]
+    fn global() {
+        let order = load_order();
+        assert_eq!(order.global(0, FormId(0x0801)), FormId(0x0801));
+        assert_eq!(order.global(1, FormId(0x0801)), FormId(0x0801));
+        assert_eq!(order.global(1, FormId(0x0100_0900)), FormId(0x0100_0900));
+
+        // Patch.esp's master 00 is Mod.esp and 01 is itself
+        assert_eq!(order.global(2, FormId(0x0900)), FormId(0x0100_0900));
+        assert_eq!(order.global(2, FormId(0x0100_0A00)), FormId(0x0200_0A00));
+
+        // past the end of the master list is the plugin itself
+        assert_eq!(order.global(1, FormId(0x0500_0001)), FormId(0x0100_0001));
+    }
+
+    #[test]
+    fn local() {
+        let order = load_order();
+        assert_eq!(order.local(0, FormId(0x0801)), Some(FormId(0x0801)));
+        assert_eq!(order.local(1, FormId(0x0100_0900)), Some(FormId(0x0100_0900)));
+        assert_eq!(order.local(2, FormId(0x0100_0900)), Some(FormId(0x0900)));
+        assert_eq!(order.local(2, FormId(0x0200_0A00)), Some(FormId(0x0100_0A00)));
+
+        // a plugin can't refer to plugins missing from its master list
+        assert_eq!(order.local(2, FormId(0x0801)), None);
+        assert_eq!(order.local(0, FormId(0x0100_0900)), None);
+
+        for (index, id) in [(1, 0x0801), (2, 0x0100_0900), (2, 0x0200_0A00)] {
+            let id = FormId(id);
+            assert_eq!(order.global(index, order.local(index, id).unwrap()), id);
+        }
+    }
+
+    #[test]
+    fn editor_ids() {
+        let order = load_order();
+        let found = |editor_id| order.editor_id(editor_id).map(|found| (found.name.as_str(), found.id));
+        assert_eq!(found("rock"), None);
+        assert_eq!(found("ROCKMOD"), Some(("RockMod", FormId(0x0801))));
+        assert_eq!(found("LampPatch"), Some(("LampPatch", FormId(0x0100_0900))));
+        assert_eq!(found("sign"), Some(("Sign", FormId(0x0200_0A00))));
+        assert_eq!(found("tree"), Some(("Tree", FormId(0x0802))));
+        assert_eq!(found("lamp"), None);
+        assert_eq!(found("Missing"), None);
+        assert_eq!(order.editor_ids().count(), 4);
+        assert!(order.editor_ids().all(|found| found.kind == *b"STAT"));
+    }
+
+    #[test]
+    fn override_order() {
+        let order = load_order();
+        assert_eq!(overrides(&order, 0x0801), [(0, "Rock".into()), (1, "RockMod".into())]);
+        assert_eq!(overrides(&order, 0x0802), [(0, "Tree".into())]);
+        assert_eq!(overrides(&order, 0x0100_0900), [(1, "Lamp".into()), (2, "LampPatch".into())]);
+        assert_eq!(overrides(&order, 0x0200_0A00), [(2, "Sign".into())]);
+        assert!(overrides(&order, 0x0100_0A00).is_empty());
+        assert!(overrides(&order, 0x0300_0001).is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+//! Forward log records into the console.
+//!
+//! `console_layer` is given to Bevy's `LogPlugin` as its custom layer. Records
+//! that pass the console filter are queued by the layer and printed by
+//! `LoggerPlugin`, warnings and errors as stderr. The filter can only narrow
+//! what `LogPlugin` itself lets through.
+//!
+//! The `logfile` command, or `WORMHOLE_LOGFILE` at startup, tees the console
+//! transcript to a file with a UTC timestamp on every line.
+
+use std::{
+    env,
+    fmt::{self, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{
+    log::{
+        tracing_subscriber::{layer::Context, registry::Registry, reload, EnvFilter, Layer},
+        BoxedLayer,
+    },
+    prelude::*,
+    utils::tracing::{self, field::{Field, Visit}, Level, Subscriber},
+};
+
+use crate::console::{complete_path, read_ordered, ConsoleArgs, ConsoleCommandExt, StdErrEvent, StdOutEvent, Stream};
+
+/// Prints queued log records and adds the `loglevel` command.
+pub struct LoggerPlugin;
+
+impl Plugin for LoggerPlugin {
+    fn build(&self, app: &mut App) {
+        info!("LoggerPlugin::build()");
+        app.register_console_command("loglevel", "Print or change the console log filter: loglevel [filter].", command_loglevel);
+        app.register_console_command("logfile", "Copy the console to a file: logfile [path|off].", command_logfile);
+        app.register_console_completer("logfile", complete_logfile);
+        app.init_resource::<LogFile>();
+        app.add_systems(Startup, logfile_env);
+        app.add_systems(Update, (
+            console_log.run_if(resource_exists::<ConsoleLog>),
+            logfile_write,
+        ));
+    }
+}
+
+//------------------------------------------------------------------------------
+
+const DEFAULT_FILTER: &str = "info";
+const LOGFILE_ENV: &str = "WORMHOLE_LOGFILE";
+const NAME: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Queued log records and the handle to change their filter.
+#[derive(Resource)]
+struct ConsoleLog {
+    records: Mutex<Receiver<(Level, String)>>, // Records waiting to be printed.
+    filter: reload::Handle<EnvFilter, Registry>, // Console filter, swapped by `loglevel`.
+    directives: String, // Current filter text.
+}
+
+/// Custom layer for `LogPlugin`, queues records for the console.
+pub fn console_layer(app: &mut App) -> Option<BoxedLayer> {
+    let (sender, receiver) = mpsc::channel();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(DEFAULT_FILTER));
+    app.insert_resource(ConsoleLog {
+        records: Mutex::new(receiver),
+        filter: handle,
+        directives: DEFAULT_FILTER.into(),
+    });
+    Some(Box::new(ConsoleLayer { sender: Mutex::new(sender) }.with_filter(filter)))
+}
+
+/// Tracing layer sending formatted records over a channel.
+struct ConsoleLayer {
+    sender: Mutex<Sender<(Level, String)>>,
+}
+
+impl<S: Subscriber> Layer<S> for ConsoleLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = format!("{:<5} {}:", metadata.level(), metadata.target());
+        event.record(&mut LineVisitor(&mut line));
+        line.push('\n');
+        if let Ok(sender) = self.sender.lock() {
+            let _ = sender.send((*metadata.level(), line)); // the app may be gone
+        }
+    }
+}
+
+/// Appends the message and then any other fields as `name=value`.
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {value:?}"),
+            name => write!(self.0, " {name}={value:?}"),
+        };
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Print queued records, warnings and errors as stderr.
+fn console_log(
+    log: Res<ConsoleLog>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    let Ok(records) = log.records.lock() else { return };
+    for (level, value) in records.try_iter() {
+        match level {
+            Level::ERROR | Level::WARN => { stderr.send(StdErrEvent::new(value)); },
+            _ => { stdout.send(StdOutEvent::new(value)); },
+        }
+    }
+}
+
+/// Print or change the console log filter, using `RUST_LOG` syntax.
+fn command_loglevel(
+    In(args): In<ConsoleArgs>,
+    log: Option<ResMut<ConsoleLog>>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=1, "[filter]") {
+        stderr.send(error);
+        return;
+    }
+    let Some(mut log) = log else {
+        stderr.send(StdErrEvent::new("loglevel: logging is disabled\n"));
+        return;
+    };
+    let Some(directives) = args.args.first() else {
+        stdout.send(StdOutEvent::new(format!("{}\n", log.directives)));
+        return;
+    };
+    let result = EnvFilter::try_new(directives)
+        .map_err(|error| error.to_string())
+        .and_then(|filter| log.filter.reload(filter).map_err(|error| error.to_string()));
+    match result {
+        Ok(()) => {
+            log.directives = directives.clone();
+            stdout.send(StdOutEvent::new(format!("loglevel: {directives}\n")));
+        },
+        Err(error) => { stderr.send(StdErrEvent::new(format!("loglevel: {error}\n"))); },
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Console transcript file, if one is open.
+#[derive(Resource, Default)]
+struct LogFile {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>, // Closed when `None`.
+}
+
+impl LogFile {
+    /// Append to a file, starting with a session header.
+    fn open(&mut self, path: PathBuf) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "# {NAME} {VERSION} session {}", timestamp(SystemTime::now()))?;
+        writer.flush()?;
+        self.path = path;
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    /// Write each line with a timestamp, marking errors.
+    fn write(&mut self, stream: Stream, value: &str) -> io::Result<()> {
+        let Some(writer) = &mut self.writer else { return Ok(()) };
+        let time = timestamp(SystemTime::now());
+        let stream = match stream {
+            Stream::Out => "out",
+            Stream::Err => "err",
+        };
+        for line in value.lines() {
+            writeln!(writer, "{time} {stream} {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a time as UTC, e.g. `2024-05-01 12:34:56.789`.
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = (since.as_secs() / 86400, since.as_secs() % 86400);
+
+    // civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}.{:03}",
+        seconds / 3600, seconds / 60 % 60, seconds % 60, since.subsec_millis())
+}
+
+/// Open the transcript named by the environment, if any.
+fn logfile_env(mut logfile: ResMut<LogFile>, mut stderr: EventWriter<StdErrEvent>) {
+    if let Some(path) = env::var_os(LOGFILE_ENV) {
+        let path = PathBuf::from(path);
+        if let Err(error) = logfile.open(path.clone()) {
+            stderr.send(StdErrEvent::new(format!("logfile: {}: {error}\n", path.display())));
+        }
+    }
+}
+
+/// Tee console output and errors to the transcript, in the order they were written.
+fn logfile_write(
+    mut stdout: EventReader<StdOutEvent>,
+    mut stderr: EventReader<StdErrEvent>,
+    mut logfile: ResMut<LogFile>,
+) {
+    if logfile.writer.is_none() {
+        stdout.clear();
+        stderr.clear();
+        return;
+    }
+    let result = read_ordered(&mut stdout, &mut stderr).into_iter()
+        .try_for_each(|(stream, value)| logfile.write(stream, value))
+        .and_then(|()| logfile.writer.as_mut().map_or(Ok(()), |writer| writer.flush()));
+    if let Err(error) = result {
+        error!("logfile: {}: {error}, closing", logfile.path.display());
+        logfile.writer = None;
+    }
+}
+
+/// Start, stop or show the console transcript.
+fn command_logfile(
+    In(args): In<ConsoleArgs>,
+    mut logfile: ResMut<LogFile>,
+    mut stdout: EventWriter<StdOutEvent>,
+    mut stderr: EventWriter<StdErrEvent>,
+) {
+    if let Err(error) = args.expect(0..=1, "[path|off]") {
+        stderr.send(error);
+        return;
+    }
+    match args.args.first().map(String::as_str) {
+        None => {
+            let value = match logfile.writer {
+                Some(_) => format!("logfile: {}\n", logfile.path.display()),
+                None => "logfile: off\n".into(),
+            };
+            stdout.send(StdOutEvent::new(value));
+        },
+        Some("off") => {
+            logfile.writer = None;
+            stdout.send(StdOutEvent::new("logfile: off\n"));
+        },
+        Some(path) => match logfile.open(path.into()) {
+            Ok(()) => { stdout.send(StdOutEvent::new(format!("logfile: {path}\n"))); },
+            Err(error) => { stderr.send(StdErrEvent::new(format!("logfile: {path}: {error}\n"))); },
+        },
+    }
+}
+
+/// Complete transcript paths for `logfile`.
+fn complete_logfile(In(args): In<ConsoleArgs>) -> Vec<String> {
+    match args.args.len() {
+        1 => complete_path(Path::new(""), &args[0], &["log", "txt"]),
+        _ => Vec::new(),
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01 00:00:00.000");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_250)), "2000-02-29 00:00:00.250");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1_792_240_496)), "2026-10-17 12:34:56.000");
+    }
+}
@@ -1,9 +1,24 @@
 //! Main executable for bevy-wormhole.
 
-use bevy::prelude::*;
+use bevy::{log::LogPlugin, prelude::*};
 
 mod console;
 use console::ConsolePlugin;
+mod cvar;
+use cvar::{CVarPlugin, CVars};
+mod logger;
+use logger::LoggerPlugin;
+mod shell;
+
+mod bsa;
+mod esm;
+mod dds;
+mod nif;
+
+mod cell;
+mod data;
+mod load_order;
+use data::DataSourcePlugin;
 
 mod crt;
 use crt::ConsolePostProcessPlugin;
@@ -16,9 +31,14 @@ use fo3::CameraUi;
 // load dev console and placeholder fo3 plugin
 fn main() {
     App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugins((ConsolePlugin, ConsolePostProcessPlugin))
-        .add_plugins(Fallout3Plugin)
+        .add_plugins(DefaultPlugins
+            .set(LogPlugin {
+                custom_layer: logger::console_layer, // copy log records to the console
+                ..default()
+            })
+            .add_before::<AssetPlugin, _>(DataSourcePlugin::default())) // after logging, before assets
+        .add_plugins((ConsolePlugin, CVarPlugin, LoggerPlugin, ConsolePostProcessPlugin))
+        .add_plugins(Fallout3Plugin::default())
         .add_systems(PostStartup, setup)
         .run();
 }
@@ -26,12 +46,13 @@ fn main() {
 fn setup(
     mut commands: Commands,
     query_camera: Query<Entity, With<CameraUi>>,
+    cvars: Res<CVars>,
 ) {
-    println!("main::setup()");
+    info!("main::setup()");
     // add crt post process effect
     for entity_id in query_camera.iter() {
-        println!("  found ui camera");
+        info!("found ui camera");
         commands.entity(entity_id)
-            .insert(PostProcessSettings::default());
+            .insert(PostProcessSettings::default().with_cvars(&cvars));
     }
 }
@@ -0,0 +1,835 @@
+//! Gamebryo NIF mesh parsing and loading, version 20.2.0.7 as used by Fallout 3 and New Vegas.
+//!
+//! A NIF is a header listing block types and sizes, the blocks themselves,
+//! and a footer naming the root blocks. Blocks reference each other by index.
+//! Only the blocks needed for static geometry are parsed, others are skipped
+//! using the sizes from the header.
+
+use std::{f32::consts::FRAC_PI_2, fmt, io};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+};
+
+use crate::dds::DdsSettings;
+
+/// The only supported file version, 20.2.0.7.
+pub const VERSION: u32 = 0x1402_0007;
+
+/// Block reference, `None` when stored as -1.
+pub type Ref = Option<usize>;
+
+/// NIF parse error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error), // Underlying reader failed.
+    Magic(String), // Header line isn't a Gamebryo or NetImmerse header.
+    Version(u32), // Unsupported file version.
+    Truncated(&'static str), // Data ended inside the named structure.
+    Block(usize, &'static str), // Block index references something invalid.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Magic(line) => write!(f, "not a nif file (found {line:?})"),
+            Error::Version(version) => write!(f, "unsupported nif version {version:08X}"),
+            Error::Truncated(what) => write!(f, "truncated {what}"),
+            Error::Block(index, what) => write!(f, "block {index}: invalid {what}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// File header.
+#[derive(Debug, Default, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub user_version: u32, // 11 for Fallout 3 and New Vegas.
+    pub bs_version: u32, // Bethesda stream version, 34 for Fallout 3.
+    pub block_types: Vec<String>, // Type name of each block.
+    pub block_sizes: Vec<u32>,
+    pub strings: Vec<String>, // String table referenced by index.
+}
+
+/// Fields shared by scene graph objects.
+#[derive(Debug, Default, Clone)]
+pub struct AvObject {
+    pub name: Option<String>,
+    #[allow(dead_code)] // bit 0 hides the object, not applied yet
+    pub flags: u32,
+    pub translation: [f32; 3],
+    pub rotation: [f32; 9], // Row major.
+    pub scale: f32,
+    pub properties: Vec<Ref>,
+}
+
+/// Scene graph node with children.
+#[derive(Debug, Default, Clone)]
+pub struct Node {
+    pub object: AvObject,
+    pub children: Vec<Ref>,
+}
+
+/// Renderable geometry, NiTriShape or NiTriStrips.
+#[derive(Debug, Default, Clone)]
+pub struct Shape {
+    pub object: AvObject,
+    pub data: Ref,
+    #[allow(dead_code)] // skinned meshes are drawn in their bind pose
+    pub skin: Ref,
+}
+
+/// Geometry data with strips already converted to triangles.
+#[derive(Debug, Default, Clone)]
+pub struct GeometryData {
+    pub vertices: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[f32; 4]>,
+    pub uvs: Vec<[f32; 2]>, // First UV set only.
+    pub triangles: Vec<[u16; 3]>,
+}
+
+/// Classic texturing property, only the base texture is kept.
+#[derive(Debug, Default, Clone)]
+pub struct TexturingProperty {
+    pub base: Ref, // NiSourceTexture
+}
+
+/// External texture reference.
+#[derive(Debug, Default, Clone)]
+pub struct SourceTexture {
+    pub file: Option<String>,
+}
+
+/// Bethesda per-pixel lighting shader.
+#[derive(Debug, Default, Clone)]
+pub struct ShaderProperty {
+    #[allow(dead_code)] // materials only use the texture set so far
+    pub shader_type: u32,
+    #[allow(dead_code)] // likewise
+    pub shader_flags: u32,
+    pub texture_set: Ref, // BSShaderTextureSet
+}
+
+/// Bethesda texture list: diffuse, normal, glow, parallax, environment, environment mask.
+#[derive(Debug, Default, Clone)]
+pub struct TextureSet {
+    pub textures: Vec<String>,
+}
+
+/// A parsed block.
+#[derive(Debug, Clone)]
+pub enum Block {
+    Node(Node), // NiNode and subclasses.
+    Shape(Shape), // NiTriShape, NiTriStrips.
+    GeometryData(GeometryData), // NiTriShapeData, NiTriStripsData.
+    TexturingProperty(TexturingProperty),
+    SourceTexture(SourceTexture),
+    ShaderProperty(ShaderProperty), // BSShaderPPLightingProperty.
+    TextureSet(TextureSet),
+    Unknown(#[allow(dead_code)] String), // Skipped, holds the type name for `Debug`.
+}
+
+/// A parsed file.
+#[derive(Debug, Clone)]
+pub struct Nif {
+    #[allow(dead_code)] // blocks are already typed and sized, the loader doesn't need it
+    pub header: Header,
+    pub blocks: Vec<Block>,
+    pub roots: Vec<Ref>,
+}
+
+impl Nif {
+    /// Parse a whole file held in memory.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut stream = Stream { bytes, strings: &[] };
+        let header = read_header(&mut stream)?;
+        stream.strings = &header.strings;
+
+        let mut blocks = Vec::with_capacity(header.block_sizes.len());
+        for (index, &size) in header.block_sizes.iter().enumerate() {
+            let Some(data) = stream.bytes.get(..size as usize) else {
+                return Err(Error::Truncated("block"));
+            };
+            stream.bytes = &stream.bytes[size as usize..];
+
+            let kind = &header.block_types[index];
+            let mut block = Stream { bytes: data, strings: &header.strings };
+            let block = read_block(&mut block, kind, header.bs_version)?;
+            if let Block::GeometryData(data) = &block {
+                // meshes index positions directly, out of range indices would panic later
+                let count = data.vertices.len();
+                if data.triangles.iter().flatten().any(|&vertex| vertex as usize >= count) {
+                    return Err(Error::Block(index, "triangle index"));
+                }
+            }
+            blocks.push(block);
+        }
+
+        let count = stream.u32()?;
+        let roots = (0..count).map(|_| stream.reference()).collect::<Result<_, _>>()?;
+
+        Ok(Nif { header, blocks, roots })
+    }
+
+    /// Block at reference, if valid.
+    pub fn get(&self, reference: Ref) -> Option<&Block> {
+        self.blocks.get(reference?)
+    }
+}
+
+//------------------------------------------------------------------------------
+
+/// Little endian cursor over block data.
+struct Stream<'a> {
+    bytes: &'a [u8],
+    strings: &'a [String],
+}
+
+impl<'a> Stream<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        let Some(bytes) = self.bytes.get(..count) else {
+            return Err(Error::Truncated("data"));
+        };
+        self.bytes = &self.bytes[count..];
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), Error> {
+        self.take(count).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn floats<const N: usize>(&mut self) -> Result<[f32; N], Error> {
+        let mut values = [0.0; N];
+        for value in &mut values {
+            *value = self.f32()?;
+        }
+        Ok(values)
+    }
+
+    fn reference(&mut self) -> Result<Ref, Error> {
+        Ok(usize::try_from(self.i32()?).ok())
+    }
+
+    fn references(&mut self) -> Result<Vec<Ref>, Error> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.reference()).collect()
+    }
+
+    /// String with a u32 length prefix.
+    fn sized_string(&mut self) -> Result<String, Error> {
+        let length = self.u32()? as usize;
+        Ok(latin1(self.take(length)?))
+    }
+
+    /// String with a u8 length prefix, including a null terminator.
+    fn short_string(&mut self) -> Result<String, Error> {
+        let length = self.u8()? as usize;
+        Ok(latin1(self.take(length)?))
+    }
+
+    /// Index into the header string table.
+    fn string(&mut self) -> Result<Option<String>, Error> {
+        let index = self.i32()?;
+        Ok(usize::try_from(index).ok().and_then(|index| self.strings.get(index)).cloned())
+    }
+}
+
+/// Decode latin-1, stopping at a null terminator.
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect()
+}
+
+/// Parse the header up to the first block.
+fn read_header(stream: &mut Stream) -> Result<Header, Error> {
+    let Some(end) = stream.bytes.iter().take(64).position(|&b| b == b'\n') else {
+        return Err(Error::Magic(latin1(&stream.bytes[..stream.bytes.len().min(40)])));
+    };
+    let line = latin1(stream.take(end + 1)?);
+    if !line.starts_with("Gamebryo File Format") && !line.starts_with("NetImmerse File Format") {
+        return Err(Error::Magic(line));
+    }
+
+    let mut header = Header { version: stream.u32()?, ..Default::default() };
+    if header.version != VERSION {
+        return Err(Error::Version(header.version));
+    }
+    stream.u8()?; // endian, always little
+    header.user_version = stream.u32()?;
+    let block_count = stream.u32()? as usize;
+
+    // Bethesda stream header
+    if header.user_version >= 3 {
+        header.bs_version = stream.u32()?;
+        stream.short_string()?; // author
+        if header.bs_version > 130 {
+            stream.u32()?;
+        }
+        if header.bs_version < 131 {
+            stream.short_string()?; // process script
+        }
+        stream.short_string()?; // export script
+        if header.bs_version == 130 {
+            stream.short_string()?; // max filepath
+        }
+    }
+
+    let type_count = stream.u16()?;
+    let types = (0..type_count).map(|_| stream.sized_string()).collect::<Result<Vec<_>, _>>()?;
+    for index in 0..block_count {
+        let kind = stream.u16()? & 0x7FFF; // high bit flags PhysX blocks
+        let Some(kind) = types.get(kind as usize) else {
+            return Err(Error::Block(index, "type"));
+        };
+        header.block_types.push(kind.clone());
+    }
+    header.block_sizes = (0..block_count).map(|_| stream.u32()).collect::<Result<_, _>>()?;
+
+    let string_count = stream.u32()?;
+    stream.u32()?; // max string length
+    header.strings = (0..string_count).map(|_| stream.sized_string()).collect::<Result<_, _>>()?;
+
+    let group_count = stream.u32()? as usize;
+    stream.skip(group_count * 4)?;
+
+    Ok(header)
+}
+
+/// Parse a block of a known type, or skip it.
+fn read_block(stream: &mut Stream, kind: &str, bs_version: u32) -> Result<Block, Error> {
+    Ok(match kind {
+        "NiNode" | "BSFadeNode" | "BSMultiBoundNode" | "BSOrderedNode" | "BSValueNode"
+        | "NiBillboardNode" | "NiSwitchNode" | "NiLODNode" | "RootCollisionNode" => {
+            let object = read_av_object(stream, bs_version)?;
+            let children = stream.references()?;
+            Block::Node(Node { object, children })
+        },
+        "NiTriShape" | "NiTriStrips" => {
+            let object = read_av_object(stream, bs_version)?;
+            let data = stream.reference()?;
+            let skin = stream.reference()?;
+            Block::Shape(Shape { object, data, skin })
+        },
+        "NiTriShapeData" | "NiTriStripsData" => {
+            Block::GeometryData(read_geometry_data(stream, kind == "NiTriStripsData", bs_version)?)
+        },
+        "NiTexturingProperty" => {
+            read_object_net(stream)?;
+            stream.u16()?; // flags
+            stream.u32()?; // texture count
+            let base = if stream.bool()? { stream.reference()? } else { None };
+            Block::TexturingProperty(TexturingProperty { base })
+        },
+        "NiSourceTexture" => {
+            read_object_net(stream)?;
+            let file = if stream.bool()? { stream.string()? } else { None };
+            Block::SourceTexture(SourceTexture { file })
+        },
+        "BSShaderPPLightingProperty" => {
+            read_object_net(stream)?;
+            stream.u16()?; // flags
+            let shader_type = stream.u32()?;
+            let shader_flags = stream.u32()?;
+            stream.u32()?; // shader flags 2
+            stream.f32()?; // environment map scale
+            stream.u32()?; // texture clamp mode
+            let texture_set = stream.reference()?;
+            Block::ShaderProperty(ShaderProperty { shader_type, shader_flags, texture_set })
+        },
+        "BSShaderTextureSet" => {
+            let count = stream.i32()?.max(0);
+            let textures = (0..count).map(|_| stream.sized_string()).collect::<Result<_, _>>()?;
+            Block::TextureSet(TextureSet { textures })
+        },
+        _ => Block::Unknown(kind.into()),
+    })
+}
+
+/// NiObjectNET, returns the name.
+fn read_object_net(stream: &mut Stream) -> Result<Option<String>, Error> {
+    let name = stream.string()?;
+    stream.references()?; // extra data
+    stream.reference()?; // controller
+    Ok(name)
+}
+
+/// NiAVObject.
+fn read_av_object(stream: &mut Stream, bs_version: u32) -> Result<AvObject, Error> {
+    let name = read_object_net(stream)?;
+    let flags = if bs_version > 26 { stream.u32()? } else { stream.u16()? as u32 };
+    let translation = stream.floats()?;
+    let rotation = stream.floats()?;
+    let scale = stream.f32()?;
+    let properties = if bs_version <= 34 { stream.references()? } else { Vec::new() };
+    stream.reference()?; // collision object
+    Ok(AvObject { name, flags, translation, rotation, scale, properties })
+}
+
+/// NiTriShapeData or NiTriStripsData.
+fn read_geometry_data(stream: &mut Stream, strips: bool, bs_version: u32) -> Result<GeometryData, Error> {
+    let mut data = GeometryData::default();
+
+    stream.i32()?; // group id
+    let count = stream.u16()? as usize;
+    stream.u8()?; // keep flags
+    stream.u8()?; // compress flags
+    if stream.bool()? {
+        data.vertices = (0..count).map(|_| stream.floats()).collect::<Result<_, _>>()?;
+    }
+    let flags = stream.u16()?;
+    // Bethesda files keep the Havok material in bits 1-5, leaving one bit for UVs
+    let uv_sets = if bs_version > 0 { flags & 1 } else { flags & 0x3F } as usize;
+    if stream.bool()? {
+        data.normals = (0..count).map(|_| stream.floats()).collect::<Result<_, _>>()?;
+        if flags & 0x1000 != 0 {
+            stream.skip(count * 12 * 2)?; // tangents, bitangents
+        }
+    }
+    stream.skip(16)?; // bounding sphere
+    if stream.bool()? {
+        data.colors = (0..count).map(|_| stream.floats()).collect::<Result<_, _>>()?;
+    }
+    for set in 0..uv_sets {
+        let uvs: Vec<[f32; 2]> = (0..count).map(|_| stream.floats()).collect::<Result<_, _>>()?;
+        if set == 0 {
+            data.uvs = uvs;
+        }
+    }
+    stream.u16()?; // consistency flags
+    stream.reference()?; // additional data
+
+    let triangle_count = stream.u16()? as usize;
+    if !strips {
+        stream.u32()?; // triangle point count
+        if stream.bool()? {
+            for _ in 0..triangle_count {
+                data.triangles.push([stream.u16()?, stream.u16()?, stream.u16()?]);
+            }
+        }
+        return Ok(data);
+    }
+
+    let strip_count = stream.u16()?;
+    let lengths = (0..strip_count).map(|_| stream.u16()).collect::<Result<Vec<_>, _>>()?;
+    if stream.bool()? {
+        for length in lengths {
+            let points = (0..length).map(|_| stream.u16()).collect::<Result<Vec<_>, _>>()?;
+            for (index, window) in points.windows(3).enumerate() {
+                let [a, b, c] = [window[0], window[1], window[2]];
+                if a == b || b == c || a == c {
+                    continue; // degenerate, used to stitch strips
+                }
+                // every other triangle in a strip is wound backwards
+                data.triangles.push(if index % 2 == 0 { [a, b, c] } else { [a, c, b] });
+            }
+        }
+    }
+    Ok(data)
+}
+
+//------------------------------------------------------------------------------
+
+/// Loads a NIF as a scene of meshes with standard materials.
+///
+/// Texture paths are resolved through the data asset source. The scene root
+/// rotates the Gamebryo Z-up axes to Bevy's Y-up.
+#[derive(Default)]
+pub struct NifLoader;
+
+impl AssetLoader for NifLoader {
+    type Asset = Scene;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Scene, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let nif = Nif::parse(&bytes)?;
+
+        let mut world = World::default();
+        let root = SpatialBundle::from_transform(Transform::from_rotation(Quat::from_rotation_x(-FRAC_PI_2)));
+        world.spawn(root).with_children(|parent| {
+            for &reference in &nif.roots {
+                spawn_block(&nif, reference, parent, load_context, 0);
+            }
+        });
+
+        Ok(Scene::new(world))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["nif"]
+    }
+}
+
+/// Guard against reference cycles in malformed files.
+const MAX_DEPTH: usize = 64;
+
+/// Spawn a node or shape and its children.
+fn spawn_block(
+    nif: &Nif,
+    reference: Ref,
+    parent: &mut WorldChildBuilder,
+    load_context: &mut LoadContext,
+    depth: usize,
+) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    match nif.get(reference) {
+        Some(Block::Node(node)) => {
+            let mut entity = parent.spawn(SpatialBundle::from_transform(transform(&node.object)));
+            if let Some(name) = &node.object.name {
+                entity.insert(Name::new(name.clone()));
+            }
+            entity.with_children(|parent| {
+                for &child in &node.children {
+                    spawn_block(nif, child, parent, load_context, depth + 1);
+                }
+            });
+        },
+        Some(Block::Shape(shape)) => {
+            let Some(Block::GeometryData(data)) = nif.get(shape.data) else { return; };
+            if data.vertices.is_empty() {
+                return;
+            }
+            let index = reference.unwrap_or_default();
+            let mesh = mesh(data);
+            let tangents = mesh.contains_attribute(Mesh::ATTRIBUTE_TANGENT);
+            let mesh = load_context.add_labeled_asset(format!("Mesh{index}"), mesh);
+            let material = load_context.labeled_asset_scope(format!("Material{index}"), |load_context| {
+                material(nif, &shape.object, tangents, load_context)
+            });
+            let mut entity = parent.spawn(PbrBundle {
+                mesh,
+                material,
+                transform: transform(&shape.object),
+                ..default()
+            });
+            if let Some(name) = &shape.object.name {
+                entity.insert(Name::new(name.clone()));
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Local transform of an object.
+fn transform(object: &AvObject) -> Transform {
+    let rotation = Mat3::from_cols_array(&object.rotation).transpose(); // stored row major
+    Transform {
+        translation: Vec3::from(object.translation),
+        rotation: Quat::from_mat3(&rotation),
+        scale: Vec3::splat(object.scale),
+    }
+}
+
+/// Build a mesh from geometry data.
+fn mesh(data: &GeometryData) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, data.vertices.clone());
+    if !data.uvs.is_empty() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, data.uvs.clone());
+    }
+    if !data.colors.is_empty() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, data.colors.clone());
+    }
+    let indices = data.triangles.iter().flatten().copied().collect();
+    mesh.insert_indices(Indices::U16(indices));
+    if data.normals.is_empty() {
+        mesh.compute_normals();
+    } else {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, data.normals.clone());
+    }
+    if !data.uvs.is_empty() {
+        let _ = mesh.generate_tangents(); // only needed for normal maps
+    }
+    mesh
+}
+
+/// Build a material from an object's texturing or shader property.
+/// Normal maps are only used when the mesh has tangents.
+fn material(nif: &Nif, object: &AvObject, tangents: bool, load_context: &mut LoadContext) -> StandardMaterial {
+    let mut material = StandardMaterial {
+        perceptual_roughness: 0.8,
+        ..default()
+    };
+    for &property in &object.properties {
+        match nif.get(property) {
+            Some(Block::TexturingProperty(texturing)) => {
+                if let Some(Block::SourceTexture(SourceTexture { file: Some(file) })) = nif.get(texturing.base) {
+                    material.base_color_texture = Some(load_context.load(texture_path(file)));
+                }
+            },
+            Some(Block::ShaderProperty(shader)) => {
+                let Some(Block::TextureSet(set)) = nif.get(shader.texture_set) else { continue; };
+                if let Some(diffuse) = set.textures.first().filter(|path| !path.is_empty()) {
+                    material.base_color_texture = Some(load_context.load(texture_path(diffuse)));
+                }
+                if let Some(normal) = set.textures.get(1).filter(|path| tangents && !path.is_empty()) {
+                    material.normal_map_texture = Some(load_context.loader()
+                        .with_settings(|settings: &mut DdsSettings| settings.is_srgb = false)
+                        .load(texture_path(normal)));
+                }
+            },
+            _ => {}
+        }
+    }
+    material
+}
+
+/// Map a texture path stored in a NIF to the data asset source.
+fn texture_path(path: &str) -> String {
+    let path = path.to_lowercase().replace('\\', "/");
+    let path = path.trim_start_matches('/');
+    let path = path.strip_prefix("data/").unwrap_or(path);
+    if path.starts_with("textures/") {
+        format!("{}://{path}", crate::data::SOURCE)
+    } else {
+        format!("{}://textures/{path}", crate::data::SOURCE)
+    }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian writer for hand-built files.
+    #[derive(Default)]
+    struct Bytes(Vec<u8>);
+
+    impl Bytes {
+        fn u8(&mut self, value: u8) -> &mut Self { self.0.push(value); self }
+        fn u16(&mut self, value: u16) -> &mut Self { self.0.extend(value.to_le_bytes()); self }
+        fn u32(&mut self, value: u32) -> &mut Self { self.0.extend(value.to_le_bytes()); self }
+        fn i32(&mut self, value: i32) -> &mut Self { self.0.extend(value.to_le_bytes()); self }
+        fn f32s(&mut self, values: &[f32]) -> &mut Self {
+            values.iter().for_each(|value| self.0.extend(value.to_le_bytes()));
+            self
+        }
+        fn sized(&mut self, value: &str) -> &mut Self { self.u32(value.len() as u32); self.0.extend(value.as_bytes()); self }
+        fn short(&mut self, value: &str) -> &mut Self { self.u8(value.len() as u8 + 1); self.0.extend(value.as_bytes()); self.u8(0) }
+        fn refs(&mut self, values: &[i32]) -> &mut Self { self.u32(values.len() as u32); values.iter().for_each(|&value| { self.i32(value); }); self }
+    }
+
+    /// A file with the given blocks, string table and roots.
+    fn file(blocks: &[(&str, Vec<u8>)], strings: &[&str], roots: &[i32]) -> Vec<u8> {
+        let mut types: Vec<&str> = Vec::new();
+        for (kind, _) in blocks {
+            if !types.contains(kind) {
+                types.push(kind);
+            }
+        }
+        let mut bytes = Bytes(b"Gamebryo File Format, Version 20.2.0.7\n".to_vec());
+        bytes.u32(VERSION).u8(1).u32(11).u32(blocks.len() as u32);
+        bytes.u32(34).short("author").short("process").short("export");
+        bytes.u16(types.len() as u16);
+        types.iter().for_each(|kind| { bytes.sized(kind); });
+        blocks.iter().for_each(|(kind, _)| { bytes.u16(types.iter().position(|k| k == kind).unwrap() as u16); });
+        blocks.iter().for_each(|(_, data)| { bytes.u32(data.len() as u32); });
+        bytes.u32(strings.len() as u32).u32(strings.iter().map(|s| s.len() as u32).max().unwrap_or(0));
+        strings.iter().for_each(|string| { bytes.sized(string); });
+        bytes.u32(0); // groups
+        blocks.iter().for_each(|(_, data)| bytes.0.extend(data));
+        bytes.refs(roots);
+        bytes.0
+    }
+
+    /// NiAVObject fields with an identity rotation.
+    fn av_object(bytes: &mut Bytes, name: i32, translation: [f32; 3], properties: &[i32]) {
+        bytes.i32(name).refs(&[]).i32(-1).u32(14);
+        bytes.f32s(&translation).f32s(&[1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]).f32s(&[2.0]);
+        bytes.refs(properties).i32(-1);
+    }
+
+    /// Geometry data up to the triangles: positions, normals and one UV set.
+    fn geometry(bytes: &mut Bytes, vertices: &[[f32; 3]]) {
+        bytes.i32(0).u16(vertices.len() as u16).u8(0).u8(0);
+        bytes.u8(1);
+        vertices.iter().for_each(|vertex| { bytes.f32s(vertex); });
+        bytes.u16(1 | 9 << 1); // one UV set, Havok material 9, no tangents
+        bytes.u8(1);
+        vertices.iter().for_each(|_| { bytes.f32s(&[0.0, 0.0, 1.0]); });
+        bytes.f32s(&[0.0; 4]); // bounding sphere
+        bytes.u8(0); // no colors
+        vertices.iter().for_each(|vertex| { bytes.f32s(&vertex[..2]); });
+        bytes.u16(0).i32(-1);
+    }
+
+    /// NiTriStripsData with the given strips over four vertices.
+    fn strips(strips: &[&[u16]]) -> Nif {
+        let mut data = Bytes::default();
+        geometry(&mut data, &[[0.0; 3]; 4]);
+        let triangles: usize = strips.iter().map(|strip| strip.len().saturating_sub(2)).sum();
+        data.u16(triangles as u16).u16(strips.len() as u16);
+        strips.iter().for_each(|strip| { data.u16(strip.len() as u16); });
+        data.u8(1);
+        strips.iter().flat_map(|strip| strip.iter()).for_each(|&point| { data.u16(point); });
+        Nif::parse(&file(&[("NiTriStripsData", data.0)], &[], &[0])).unwrap()
+    }
+
+    fn triangles(nif: &Nif) -> &[[u16; 3]] {
+        match &nif.blocks[0] {
+            Block::GeometryData(data) => &data.triangles,
+            block => panic!("expected geometry data, found {block:?}"),
+        }
+    }
+
+    #[test]
+    fn header() {
+        let blocks = [("NiNode", vec![1, 2]), ("NiExtraData", vec![3]), ("NiNode", vec![])];
+        let mut stream = Stream { bytes: &file(&blocks, &["Scene Root", "Rock"], &[]), strings: &[] };
+        let header = read_header(&mut stream).unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!((header.user_version, header.bs_version), (11, 34));
+        assert_eq!(header.block_types, ["NiNode", "NiExtraData", "NiNode"]);
+        assert_eq!(header.block_sizes, [2, 1, 0]);
+        assert_eq!(header.strings, ["Scene Root", "Rock"]);
+        assert_eq!(stream.bytes, [1, 2, 3, 0, 0, 0, 0]); // blocks then an empty root list
+    }
+
+    #[test]
+    fn header_errors() {
+        assert!(matches!(Nif::parse(b"Not a mesh\n"), Err(Error::Magic(_))));
+        assert!(matches!(Nif::parse(&[0; 80]), Err(Error::Magic(_))));
+
+        let mut bytes = file(&[], &[], &[]);
+        bytes[39] = 0x06; // 20.2.0.6
+        assert!(matches!(Nif::parse(&bytes), Err(Error::Version(0x1402_0006))));
+
+        let bytes = file(&[("NiNode", vec![0; 8])], &[], &[0]);
+        assert!(matches!(Nif::parse(&bytes[..bytes.len() - 12]), Err(Error::Truncated(_))));
+    }
+
+    #[test]
+    fn blocks() {
+        let mut node = Bytes::default();
+        av_object(&mut node, 0, [1.0, 2.0, 3.0], &[]);
+        node.refs(&[1, -1]);
+
+        let mut shape = Bytes::default();
+        av_object(&mut shape, 1, [0.0; 3], &[3]);
+        shape.i32(2).i32(-1);
+
+        let mut data = Bytes::default();
+        geometry(&mut data, &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]]);
+        data.u16(2).u32(6).u8(1).u16(0).u16(1).u16(2).u16(2).u16(1).u16(3);
+
+        let blocks = [
+            ("BSFadeNode", node.0),
+            ("NiTriShape", shape.0),
+            ("NiTriShapeData", data.0),
+            ("NiMaterialProperty", vec![0; 12]),
+        ];
+        let nif = Nif::parse(&file(&blocks, &["Scene Root", "Rock"], &[0])).unwrap();
+        assert_eq!(nif.roots, [Some(0)]);
+
+        let Some(Block::Node(node)) = nif.get(Some(0)) else { panic!("expected a node") };
+        assert_eq!(node.object.name.as_deref(), Some("Scene Root"));
+        assert_eq!(node.object.flags, 14);
+        assert_eq!(node.object.translation, [1.0, 2.0, 3.0]);
+        assert_eq!(node.object.scale, 2.0);
+        assert_eq!(node.children, [Some(1), None]);
+
+        let Some(Block::Shape(shape)) = nif.get(node.children[0]) else { panic!("expected a shape") };
+        assert_eq!(shape.object.name.as_deref(), Some("Rock"));
+        assert_eq!(shape.object.properties, [Some(3)]);
+        assert_eq!((shape.data, shape.skin), (Some(2), None));
+
+        let Some(Block::GeometryData(data)) = nif.get(shape.data) else { panic!("expected geometry data") };
+        assert_eq!(data.vertices.len(), 4);
+        assert_eq!(data.vertices[3], [1.0, 1.0, 0.0]);
+        assert_eq!(data.normals, [[0.0, 0.0, 1.0]; 4]);
+        assert_eq!(data.uvs[1], [1.0, 0.0]);
+        assert!(data.colors.is_empty());
+        assert_eq!(data.triangles, [[0, 1, 2], [2, 1, 3]]);
+
+        assert!(matches!(nif.get(Some(3)), Some(Block::Unknown(kind)) if kind == "NiMaterialProperty"));
+        assert!(nif.get(None).is_none() && nif.get(Some(4)).is_none());
+    }
+
+    #[test]
+    fn triangle_range() {
+        let mut data = Bytes::default();
+        geometry(&mut data, &[[0.0; 3]; 3]);
+        data.u16(1).u32(3).u8(1).u16(0).u16(1).u16(3);
+        let bytes = file(&[("NiTriShapeData", data.0)], &[], &[0]);
+        assert!(matches!(Nif::parse(&bytes), Err(Error::Block(0, "triangle index"))));
+
+        // no vertices at all
+        let mut data = Bytes::default();
+        geometry(&mut data, &[]);
+        data.u16(1).u32(3).u8(1).u16(0).u16(0).u16(0);
+        let bytes = file(&[("NiTriShapeData", data.0)], &[], &[0]);
+        assert!(matches!(Nif::parse(&bytes), Err(Error::Block(0, "triangle index"))));
+    }
+
+    #[test]
+    fn strip_winding() {
+        // odd triangles are flipped to keep a consistent winding
+        assert_eq!(triangles(&strips(&[&[0, 1, 2, 3]])), [[0, 1, 2], [1, 3, 2]]);
+        assert_eq!(triangles(&strips(&[&[0, 1, 2, 3, 0]])), [[0, 1, 2], [1, 3, 2], [2, 3, 0]]);
+        assert!(triangles(&strips(&[&[0, 1], &[2]])).is_empty());
+    }
+
+    #[test]
+    fn strip_degenerate() {
+        // repeated points stitch strips together and produce no triangles
+        let separate = strips(&[&[0, 1, 2], &[3, 1, 0]]);
+        assert_eq!(triangles(&separate), [[0, 1, 2], [3, 1, 0]]);
+        let stitched = strips(&[&[0, 1, 2, 2, 2, 3, 3, 1, 0]]);
+        assert_eq!(triangles(&stitched), triangles(&separate));
+
+        // parity still counts the degenerates
+        assert_eq!(triangles(&strips(&[&[0, 1, 2, 2, 3, 3, 1, 0]])), [[0, 1, 2], [3, 0, 1]]);
+    }
+}
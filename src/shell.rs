@@ -0,0 +1,208 @@
+//! Shell style command line parsing.
+//!
+//! Whitespace separates tokens and `;` separates commands. Single quotes keep
+//! everything literally, double quotes allow `\"` and `\\` escapes, and a
+//! backslash outside quotes escapes any character.
+
+use std::fmt;
+
+/// Command line parse error, positions are character offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnterminatedQuote(char, usize), // Quote character and where it opened.
+    TrailingEscape(usize), // Backslash at the end of the line.
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote, position) => write!(f, "unterminated {quote} at column {}", position + 1),
+            ParseError::TrailingEscape(position) => write!(f, "trailing \\ at column {}", position + 1),
+        }
+    }
+}
+
+/// Split a line into commands, each a non-empty list of tokens.
+pub fn parse(line: &str) -> Result<Vec<Vec<String>>, ParseError> {
+    let mut scan = scan(line);
+    if let Some(error) = scan.error {
+        return Err(error);
+    }
+    scan.tokens.extend(scan.token);
+    if !scan.tokens.is_empty() {
+        scan.commands.push(scan.tokens);
+    }
+    Ok(scan.commands.into_iter()
+        .map(|tokens| tokens.into_iter().map(|(_, token)| token).collect())
+        .collect())
+}
+
+/// The command being typed at the end of a line, for completion.
+///
+/// Returns its finished tokens, and the byte offset and text of the last
+/// token, which may be empty or have an unterminated quote.
+pub fn partial(line: &str) -> (Vec<String>, usize, String) {
+    let scan = scan(line);
+    let tokens = scan.tokens.into_iter().map(|(_, token)| token).collect();
+    let (start, token) = scan.token.unwrap_or((line.len(), String::new()));
+    (tokens, start, token)
+}
+
+/// Escape a token so `parse` reads it back unchanged.
+pub fn escape(token: &str) -> String {
+    if token.is_empty() {
+        return "''".into();
+    }
+    let mut escaped = String::with_capacity(token.len());
+    for c in token.chars() {
+        if c.is_whitespace() || matches!(c, ';' | '\\' | '\'' | '"') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Tokens with the byte offset each starts at.
+type Tokens = Vec<(usize, String)>;
+
+/// A line split as far as it goes.
+struct Scan {
+    commands: Vec<Tokens>, // Commands ended by `;`, non-empty.
+    tokens: Tokens, // Finished tokens of the last command.
+    token: Option<(usize, String)>, // Token at the end of the line, quotes can start an empty one.
+    error: Option<ParseError>, // Why the line ended inside a token.
+}
+
+/// Split a line, keeping what was read when it ends inside a token.
+fn scan(line: &str) -> Scan {
+    let mut scan = Scan { commands: Vec::new(), tokens: Vec::new(), token: None, error: None };
+    let mut chars = line.char_indices().enumerate();
+
+    while let Some((position, (offset, c))) = chars.next() {
+        match c {
+            ';' => {
+                scan.tokens.extend(scan.token.take());
+                if !scan.tokens.is_empty() {
+                    scan.commands.push(std::mem::take(&mut scan.tokens));
+                }
+            },
+            c if c.is_whitespace() => scan.tokens.extend(scan.token.take()),
+            '\\' => {
+                let token = &mut scan.token.get_or_insert_with(|| (offset, String::new())).1;
+                let Some((_, (_, escaped))) = chars.next() else {
+                    scan.error = Some(ParseError::TrailingEscape(position));
+                    break;
+                };
+                token.push(escaped);
+            },
+            '\'' => {
+                let token = &mut scan.token.get_or_insert_with(|| (offset, String::new())).1;
+                loop {
+                    match chars.next() {
+                        Some((_, (_, '\''))) => break,
+                        Some((_, (_, c))) => token.push(c),
+                        None => {
+                            scan.error = Some(ParseError::UnterminatedQuote('\'', position));
+                            break;
+                        },
+                    }
+                }
+            },
+            '"' => {
+                let token = &mut scan.token.get_or_insert_with(|| (offset, String::new())).1;
+                loop {
+                    match chars.next() {
+                        Some((_, (_, '"'))) => break,
+                        Some((_, (_, '\\'))) => match chars.next() {
+                            Some((_, (_, c @ ('"' | '\\')))) => token.push(c),
+                            Some((_, (_, c))) => token.extend(['\\', c]),
+                            None => {
+                                scan.error = Some(ParseError::UnterminatedQuote('"', position));
+                                break;
+                            },
+                        },
+                        Some((_, (_, c))) => token.push(c),
+                        None => {
+                            scan.error = Some(ParseError::UnterminatedQuote('"', position));
+                            break;
+                        },
+                    }
+                }
+            },
+            c => scan.token.get_or_insert_with(|| (offset, String::new())).1.push(c),
+        }
+    }
+    scan
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(line: &str) -> Vec<Vec<String>> {
+        parse(line).unwrap()
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(commands("  load   Fallout3.esm \t"), [["load", "Fallout3.esm"]]);
+        assert!(commands("").is_empty());
+        assert!(commands("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(commands(r#"load "Fallout 3/Data/Fallout3.esm""#), [["load", "Fallout 3/Data/Fallout3.esm"]]);
+        assert_eq!(commands("coc 'Vault 101'"), [["coc", "Vault 101"]]);
+        assert_eq!(commands(r#"a"b c"'d e'f"#), [["ab cd ef"]]);
+        assert_eq!(commands(r#"echo "" ''"#), [["echo", "", ""]]);
+        assert_eq!(commands(r#"echo "it's" 'say "hi"'"#), [["echo", "it's", r#"say "hi""#]]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(commands(r"load Fallout\ 3/Fallout3.esm"), [["load", "Fallout 3/Fallout3.esm"]]);
+        assert_eq!(commands(r#"echo \"a\" \; \\"#), [["echo", "\"a\"", ";", "\\"]]);
+        assert_eq!(commands(r#"echo "a\"b\\c\d""#), [["echo", r#"a"b\c\d"#]]);
+        assert_eq!(commands(r"echo 'a\b'"), [["echo", r"a\b"]]);
+    }
+
+    #[test]
+    fn chaining() {
+        assert_eq!(commands("load a.esm; coc Vault101"), [vec!["load", "a.esm"], vec!["coc", "Vault101"]]);
+        assert_eq!(commands("help;version"), [["help"], ["version"]]);
+        assert_eq!(commands(";; help ; ;"), [["help"]]);
+        assert_eq!(commands("echo 'a;b' \"c;d\""), [["echo", "a;b", "c;d"]]);
+    }
+
+    #[test]
+    fn partials() {
+        assert_eq!(partial(""), (vec![], 0, String::new()));
+        assert_eq!(partial("load Fall"), (vec!["load".into()], 5, "Fall".into()));
+        assert_eq!(partial("load "), (vec!["load".into()], 5, String::new()));
+        assert_eq!(partial("help; coc 'Vault 1"), (vec!["coc".into()], 10, "Vault 1".into()));
+        assert_eq!(partial(r"load Fallout\ 3/Fa"), (vec!["load".into()], 5, "Fallout 3/Fa".into()));
+        assert_eq!(partial("help;"), (vec![], 5, String::new()));
+    }
+
+    #[test]
+    fn escaping() {
+        for token in ["Vault 101", "it's \"quoted\"", r"a\b;c", "", "plain.esm"] {
+            assert_eq!(commands(&format!("coc {}", escape(token))), [["coc", token]]);
+        }
+        assert_eq!(escape("Vault 101"), r"Vault\ 101");
+        assert_eq!(escape("Fallout3.esm"), "Fallout3.esm");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("load \"Fallout 3"), Err(ParseError::UnterminatedQuote('"', 5)));
+        assert_eq!(parse("coc 'Vault"), Err(ParseError::UnterminatedQuote('\'', 4)));
+        assert_eq!(parse(r#"echo "a\"#), Err(ParseError::UnterminatedQuote('"', 5)));
+        assert_eq!(parse(r"echo \"), Err(ParseError::TrailingEscape(5)));
+        assert_eq!(ParseError::TrailingEscape(5).to_string(), r"trailing \ at column 6");
+    }
+}